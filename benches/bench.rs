@@ -31,7 +31,7 @@ fn bench(c: &mut Criterion) {
     ]
     .iter()
     {
-        let path = Path::as_os_str(*path);
+        let path = Path::as_os_str(path);
 
         group.bench_with_input(
             BenchmarkId::new("realpath_ext::realpath_raw", ident),
@@ -58,6 +58,20 @@ fn bench(c: &mut Criterion) {
             },
         );
 
+        #[cfg(all(feature = "std", target_os = "linux"))]
+        group.bench_with_input(
+            BenchmarkId::new("realpath_ext::RealpathBuilder (openat2)", ident),
+            path,
+            |b, i| {
+                let mut builder = realpath_ext::RealpathBuilder::new();
+                builder.use_openat2(true);
+                b.iter(|| {
+                    let path = builder.realpath(i).unwrap();
+                    black_box(path);
+                })
+            },
+        );
+
         group.bench_with_input(BenchmarkId::new("fs::canonicalize", ident), path, |b, i| {
             b.iter(|| {
                 let path = fs::canonicalize(i).unwrap();
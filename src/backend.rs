@@ -0,0 +1,320 @@
+use core::ffi::CStr;
+
+use crate::slicevec::SliceVec;
+use crate::util;
+
+/// The filesystem operations used to resolve paths.
+///
+/// By default, paths are resolved against the OS's filesystem (see [`LibcBackend`]). Implementing
+/// this trait allows running exactly the same resolution algorithm against something else (for
+/// example, a remote mirror, an archive, or a test fixture). Pass a backend to
+/// [`RealpathBuilder::backend()`](crate::RealpathBuilder::backend) or
+/// [`RealpathRawBuilder::backend()`](crate::RealpathRawBuilder::backend).
+///
+/// All paths passed to these methods are either absolute or relative to the "base directory"
+/// (the equivalent of the current working directory), and relative paths may begin with `../`.
+/// With [`RealpathFlags::IN_ROOT`](crate::RealpathFlags::IN_ROOT), they are always relative to
+/// the base directory (which acts as the root).
+///
+/// Errors are reported as OS error codes, and should follow the conventions of the corresponding
+/// libc functions (for example, `ENOENT` if a path does not exist, or `ENOTDIR` if a component
+/// of it is not a directory).
+pub trait FsBackend {
+    /// Read the contents of the symbolic link `path` into `buf`, returning the number of bytes
+    /// read.
+    ///
+    /// This should behave like `readlink()`: it should fail with `EINVAL` if `path` is not a
+    /// symbolic link, and if the contents do not fit in `buf` they should be truncated to
+    /// `buf.len()` bytes.
+    fn readlink(&self, path: &CStr, buf: &mut [u8]) -> Result<usize, i32>;
+
+    /// Check whether `path` (following symbolic links) refers to a directory, failing with
+    /// `ENOTDIR` if it does not.
+    fn check_isdir(&self, path: &CStr) -> Result<(), i32>;
+
+    /// Store the canonicalized absolute path to the base directory in `buf`, returning its length.
+    ///
+    /// This should fail with `ENAMETOOLONG` if the path does not fit in `buf`.
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32>;
+
+    /// Get the ID of the device that `path` (which is not followed if it is a symbolic link)
+    /// resides on.
+    ///
+    /// This is only used with [`RealpathFlags::NO_XDEV`](crate::RealpathFlags::NO_XDEV). The
+    /// default implementation fails with `ENOTSUP`.
+    #[inline]
+    fn device(&self, path: &CStr) -> Result<u64, i32> {
+        let _ = path;
+        Err(libc::ENOTSUP)
+    }
+
+    /// Get the device and inode numbers of `path` (which is not followed if it is a symbolic
+    /// link).
+    ///
+    /// This is only used to identify symbolic links when looking for loops (see
+    /// [`RealpathBuilder::detect_symlink_cycles()`](crate::RealpathBuilder::detect_symlink_cycles)).
+    /// The default implementation fails with `ENOTSUP`, in which case links are identified by
+    /// their paths instead.
+    #[inline]
+    fn file_id(&self, path: &CStr) -> Result<(u64, u64), i32> {
+        let _ = path;
+        Err(libc::ENOTSUP)
+    }
+
+    /// Check whether the symbolic link `path` resides on a procfs filesystem (so it may be a
+    /// "magic link").
+    ///
+    /// This is only used on Linux and Android (see
+    /// [`RealpathFlags::NO_MAGICLINKS`](crate::RealpathFlags::NO_MAGICLINKS)). The default
+    /// implementation returns `false`.
+    #[inline]
+    fn is_procfs(&self, path: &CStr) -> Result<bool, i32> {
+        let _ = path;
+        Ok(false)
+    }
+
+    // Like `getcwd()`, but never returns a path with symbolic links in it (even if `getcwd()`
+    // does), because it's about to be shortened for leading `..` components that were resolved by
+    // the backend
+    #[doc(hidden)]
+    #[inline]
+    fn getcwd_physical(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        self.getcwd(buf)
+    }
+}
+
+/// The default [`FsBackend`], which uses the OS's filesystem.
+///
+/// Relative paths are resolved against the directory referred to by a file descriptor (or the
+/// current working directory) with the `*at()` family of functions, as described for
+/// [`realpathat()`](crate::realpathat).
+#[derive(Clone, Copy, Debug)]
+pub struct LibcBackend {
+    dirfd: libc::c_int,
+}
+
+impl LibcBackend {
+    /// Create a backend that resolves relative paths against the current working directory.
+    #[inline]
+    pub const fn new() -> Self {
+        Self::with_dirfd(libc::AT_FDCWD)
+    }
+
+    /// Create a backend that resolves relative paths against the directory referred to by
+    /// `dirfd` (which may be `AT_FDCWD`).
+    ///
+    /// The file descriptor is not closed when the backend is dropped.
+    #[inline]
+    pub const fn with_dirfd(dirfd: libc::c_int) -> Self {
+        Self { dirfd }
+    }
+}
+
+impl Default for LibcBackend {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FsBackend for LibcBackend {
+    #[inline]
+    fn readlink(&self, path: &CStr, buf: &mut [u8]) -> Result<usize, i32> {
+        unsafe { util::readlinkat(self.dirfd, path.as_ptr() as *const u8, buf) }
+    }
+
+    #[inline]
+    fn check_isdir(&self, path: &CStr) -> Result<(), i32> {
+        unsafe { util::check_isdir(self.dirfd, path.as_ptr() as *const u8) }
+    }
+
+    #[inline]
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        let mut buf = SliceVec::empty(buf);
+        util::getdirpath(self.dirfd, &mut buf)?;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn device(&self, path: &CStr) -> Result<u64, i32> {
+        #[allow(clippy::unnecessary_cast)]
+        unsafe { util::get_dev(self.dirfd, path.as_ptr() as *const u8) }.map(|dev| dev as u64)
+    }
+
+    #[inline]
+    fn file_id(&self, path: &CStr) -> Result<(u64, u64), i32> {
+        #[allow(clippy::unnecessary_cast)]
+        unsafe { util::get_file_id(self.dirfd, path.as_ptr() as *const u8) }
+            .map(|(dev, ino)| (dev as u64, ino as u64))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[inline]
+    fn is_procfs(&self, path: &CStr) -> Result<bool, i32> {
+        unsafe { util::is_procfs(self.dirfd, path.as_ptr() as *const u8) }
+    }
+}
+
+/// A [`LibcBackend`] whose base directory is already known to be at a certain path, so it never
+/// has to be looked up with `getcwd()`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub(crate) struct KnownCwdBackend<'a> {
+    pub inner: LibcBackend,
+    /// The path to the base directory (if known)
+    pub cwd: Option<std::borrow::Cow<'a, [u8]>>,
+    /// Whether `cwd` may contain symbolic links (because it was taken from `$PWD`)
+    pub symlinks: bool,
+    /// Whether leading `..` components have to be resolved against `cwd` lexically (instead of by
+    /// the OS), as with [`RealpathFlags::LOGICAL`](crate::RealpathFlags::LOGICAL)
+    pub logical: bool,
+}
+
+#[cfg(feature = "std")]
+impl KnownCwdBackend<'_> {
+    /// Create a backend for the current working directory that takes its path from `$PWD` (if
+    /// that is valid; see [`util::is_logical_cwd()`]).
+    ///
+    /// If `logical` is `true`, leading `..` components are resolved against `$PWD` lexically.
+    /// Otherwise, they are resolved by the OS, and the path to the current working directory is
+    /// taken from `getcwd()` instead whenever they are present.
+    pub fn from_pwd(logical: bool) -> Self {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        let cwd = std::env::var_os("PWD").and_then(|pwd| {
+            let mut pwd = pwd.into_vec();
+            pwd.push(b'\0');
+            if !matches!(util::cstr(&pwd), Ok(pwd) if util::is_logical_cwd(pwd)) {
+                return None;
+            }
+            pwd.pop();
+            Some(pwd.into())
+        });
+
+        Self {
+            inner: LibcBackend::new(),
+            cwd,
+            symlinks: true,
+            logical,
+        }
+    }
+
+    /// Call `f` with `path`, or (if it begins with `..` and must be resolved lexically) the
+    /// equivalent absolute path.
+    fn with_path<R>(&self, path: &CStr, f: impl FnOnce(&CStr) -> Result<R, i32>) -> Result<R, i32> {
+        let cwd = match self.cwd.as_ref() {
+            Some(cwd) if self.logical && path.to_bytes().starts_with(b"..") => cwd,
+            _ => return f(path),
+        };
+
+        let mut rest = path.to_bytes();
+        let mut abs = cwd.to_vec();
+        while rest == b".." || rest.starts_with(b"../") {
+            rest = util::strip_leading_slashes(&rest[2..]);
+            let len = abs.iter().rposition(|&ch| ch == b'/').unwrap_or(0);
+            abs.truncate(len.max(1));
+        }
+        if !rest.is_empty() {
+            if abs != b"/" {
+                abs.push(b'/');
+            }
+            abs.extend_from_slice(rest);
+        }
+        abs.push(b'\0');
+
+        f(util::cstr(&abs)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl FsBackend for KnownCwdBackend<'_> {
+    #[inline]
+    fn readlink(&self, path: &CStr, buf: &mut [u8]) -> Result<usize, i32> {
+        self.with_path(path, |path| self.inner.readlink(path, buf))
+    }
+
+    #[inline]
+    fn check_isdir(&self, path: &CStr) -> Result<(), i32> {
+        self.with_path(path, |path| self.inner.check_isdir(path))
+    }
+
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        match self.cwd.as_ref() {
+            Some(cwd) => {
+                buf.get_mut(..cwd.len())
+                    .ok_or(libc::ENAMETOOLONG)?
+                    .copy_from_slice(cwd);
+                Ok(cwd.len())
+            }
+            None => self.inner.getcwd(buf),
+        }
+    }
+
+    fn getcwd_physical(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        if self.symlinks && !self.logical {
+            self.inner.getcwd(buf)
+        } else {
+            self.getcwd(buf)
+        }
+    }
+
+    #[inline]
+    fn device(&self, path: &CStr) -> Result<u64, i32> {
+        self.with_path(path, |path| self.inner.device(path))
+    }
+
+    #[inline]
+    fn file_id(&self, path: &CStr) -> Result<(u64, u64), i32> {
+        self.with_path(path, |path| self.inner.file_id(path))
+    }
+
+    #[inline]
+    fn is_procfs(&self, path: &CStr) -> Result<bool, i32> {
+        self.with_path(path, |path| self.inner.is_procfs(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_libc_backend() {
+        let backend = LibcBackend::new();
+        let mut buf = [0; 4096];
+
+        assert_eq!(
+            backend.readlink(util::cstr(b"/\0").unwrap(), &mut buf),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            backend.check_isdir(util::cstr(b"/bin/sh\0").unwrap()),
+            Err(libc::ENOTDIR)
+        );
+        backend.check_isdir(util::cstr(b"/\0").unwrap()).unwrap();
+        assert_eq!(
+            backend.device(util::cstr(b"/\0").unwrap()).unwrap(),
+            backend.device(util::cstr(b"/.\0").unwrap()).unwrap()
+        );
+
+        let n = backend.getcwd(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"/"));
+        assert_eq!(backend.getcwd(&mut []), Err(libc::ENAMETOOLONG));
+
+        let root = unsafe { libc::open(b"/\0".as_ptr() as *const _, libc::O_RDONLY) };
+        assert!(root >= 0);
+        let backend = LibcBackend::with_dirfd(root);
+        backend.check_isdir(util::cstr(b"etc\0").unwrap()).unwrap();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let n = backend.getcwd(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"/");
+        }
+        unsafe {
+            libc::close(root);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(target_family = "unix")]
+use std::os::unix::prelude::*;
+#[cfg(target_os = "wasi")]
+use std::os::wasi::prelude::*;
+
+use crate::resolver::{Resolver, Snapshot};
+use crate::util::{self, ComponentIter, Limits};
+use crate::{FsBackend, RealpathError, RealpathFlags, PATH_MAX};
+
+/// A prefix of a path that has already been resolved.
+#[derive(Debug)]
+struct Saved {
+    /// The prefix itself (which ends at the start of a component)
+    key: Vec<u8>,
+    /// What `key` resolved to
+    resolved: Vec<u8>,
+    snapshot: Snapshot,
+}
+
+/// Check whether the state saved after resolving `prefix` (which ends at the start of a
+/// component) can be used to skip the start of `path`.
+fn is_reusable(prefix: &[u8], path: &[u8], flags: RealpathFlags) -> bool {
+    if prefix.is_empty() || !path.starts_with(prefix) || path[prefix.len()..].starts_with(b"/") {
+        return false;
+    }
+
+    let rest = match ComponentIter::new(&path[prefix.len()..]) {
+        Ok(rest) => rest,
+        Err(_) => return false,
+    };
+
+    // If there are no more components after the prefix, the last component of the prefix may
+    // have been handled differently (e.g. with ALLOW_LAST_MISSING)
+    if !flags.contains(RealpathFlags::LOGICAL) {
+        return !rest.is_empty();
+    } else if util::count_logical(rest.as_bytes(), |_| ()).0 == 0 {
+        return false;
+    }
+
+    // With LOGICAL, `..` components in the rest of the path must not cancel out components of
+    // the prefix
+    let mut depth = 0usize;
+    for component in rest {
+        if component == b".." {
+            match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            }
+        } else {
+            depth += 1;
+        }
+    }
+    true
+}
+
+/// Get the offsets of the start of every component of `path` (except the first).
+fn component_starts(path: &[u8]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    if let Ok(mut it) = ComponentIter::new(path) {
+        while it.next().is_some() {
+            starts.push(path.len() - it.as_bytes().len());
+        }
+        // The last offset is the end of the path
+        starts.pop();
+    }
+    starts
+}
+
+/// Resolves a series of paths, reusing the resolved forms of the prefixes shared by consecutive
+/// paths.
+#[derive(Debug)]
+pub(crate) struct BatchResolver<'b, B: ?Sized> {
+    backend: &'b B,
+    flags: RealpathFlags,
+    limits: Limits,
+    /// Whether to look for loops of symbolic links
+    detect_loops: bool,
+    max_len: usize,
+    buf: Vec<u8>,
+    tmp: Vec<u8>,
+    /// Each entry's `key` is a prefix of the next entry's `key`
+    saved: Vec<Saved>,
+}
+
+impl<'b, B: FsBackend + ?Sized> BatchResolver<'b, B> {
+    pub fn new(
+        backend: &'b B,
+        flags: RealpathFlags,
+        limits: Limits,
+        detect_loops: bool,
+        max_len: usize,
+    ) -> Self {
+        let len = PATH_MAX.min(max_len);
+
+        Self {
+            backend,
+            flags,
+            limits,
+            detect_loops,
+            max_len,
+            buf: vec![0; len],
+            tmp: vec![0; len + 100],
+            saved: Vec::new(),
+        }
+    }
+
+    /// Resolve `path`.
+    ///
+    /// `next` should be the path that will be resolved next (if any); the prefixes that it shares
+    /// with `path` are saved so that they don't have to be resolved again.
+    pub fn resolve(&mut self, path: &Path, next: Option<&Path>) -> Result<PathBuf, RealpathError> {
+        let path = path.as_os_str().as_bytes();
+        let next = next.map(|next| next.as_os_str().as_bytes());
+
+        while let Some(saved) = self.saved.last() {
+            if is_reusable(&saved.key, path, self.flags) {
+                break;
+            }
+            self.saved.pop();
+        }
+
+        loop {
+            match self.try_resolve(path, next) {
+                Ok(len) => {
+                    let path = self.buf[..len].to_vec();
+                    return Ok(std::ffi::OsString::from_vec(path).into());
+                }
+
+                Err(err) if err.errno() == libc::ENAMETOOLONG && self.buf.len() < self.max_len => {
+                    // Resize until we hit the maximum limit
+                    let new_len = self.buf.len().saturating_mul(2).min(self.max_len);
+                    self.buf.resize(new_len, 0);
+                    self.tmp.resize(new_len + 100, 0);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn try_resolve(&mut self, path: &[u8], next: Option<&[u8]>) -> Result<usize, RealpathError> {
+        let flags = self.flags;
+        let mut resolver = Resolver::new(path, &mut self.buf, &mut self.tmp, flags)?;
+        resolver.set_limits(self.limits);
+        // Links followed while resolving a saved prefix can't be part of a loop (the rest of the
+        // path after them still included the rest of the prefix), so they don't need to be seen
+        if self.detect_loops {
+            resolver.detect_loops();
+        }
+
+        let start = match self.saved.last() {
+            Some(saved) => {
+                resolver.restore(saved.key.len(), saved.snapshot, &saved.resolved)?;
+                saved.key.len()
+            }
+            None => 0,
+        };
+
+        // Save the state at the start of every component that `next` has in common with `path`
+        let mut pauses = match next {
+            Some(next) => component_starts(path)
+                .into_iter()
+                .filter(|&offset| offset > start && is_reusable(&path[..offset], next, flags))
+                .collect(),
+            None => Vec::new(),
+        }
+        .into_iter();
+
+        let mut pause = pauses.next();
+        if let Some(offset) = pause {
+            resolver.pause_at(offset);
+        }
+
+        loop {
+            if let Some(len) = crate::run_resolver(&mut resolver, self.backend, &mut ())? {
+                return Ok(len);
+            }
+
+            let offset = pause.expect("resolution paused unexpectedly");
+            let (snapshot, resolved) = resolver.snapshot();
+            self.saved.push(Saved {
+                key: path[..offset].to_vec(),
+                resolved: resolved.to_vec(),
+                snapshot,
+            });
+
+            pause = pauses.next();
+            if let Some(offset) = pause {
+                resolver.pause_at(offset);
+            }
+        }
+    }
+}
+
+/// Resolve the paths with the given indices (which should be sorted by path), passing each
+/// result to `f`.
+fn resolve_sorted<B: FsBackend + ?Sized>(
+    resolver: &mut BatchResolver<'_, B>,
+    paths: &[&Path],
+    order: &[usize],
+    mut f: impl FnMut(usize, Result<PathBuf, RealpathError>),
+) {
+    for (j, &i) in order.iter().enumerate() {
+        let next = order.get(j + 1).map(|&i| paths[i]);
+        f(i, resolver.resolve(paths[i], next));
+    }
+}
+
+/// Resolve all of the given `paths` with up to `threads` threads, returning the results in the
+/// same order.
+pub(crate) fn resolve_many<B: FsBackend + Sync + ?Sized>(
+    backend: &B,
+    paths: &[&Path],
+    flags: RealpathFlags,
+    limits: Limits,
+    detect_loops: bool,
+    max_len: usize,
+    threads: usize,
+) -> Vec<Result<PathBuf, RealpathError>> {
+    // Sorting the paths puts paths with common prefixes next to each other
+    let mut order = (0..paths.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| paths[i]);
+
+    let mut results = paths.iter().map(|_| None).collect::<Vec<_>>();
+
+    if threads <= 1 || paths.len() <= 1 {
+        let mut resolver = BatchResolver::new(backend, flags, limits, detect_loops, max_len);
+        resolve_sorted(&mut resolver, paths, &order, |i, res| {
+            results[i] = Some(res)
+        });
+    } else {
+        // Threads take small runs of consecutive paths (so prefixes can still be shared) until
+        // there are none left, so one thread getting stuck on slow paths doesn't hold up the rest
+        let chunks = order
+            .chunks((paths.len() / (threads * 8)).clamp(1, 256))
+            .collect::<Vec<_>>();
+        let next_chunk = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            let handles = (0..threads.min(chunks.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut resolver =
+                            BatchResolver::new(backend, flags, limits, detect_loops, max_len);
+                        let mut out = Vec::new();
+                        while let Some(chunk) =
+                            chunks.get(next_chunk.fetch_add(1, Ordering::Relaxed))
+                        {
+                            resolve_sorted(&mut resolver, paths, chunk, |i, res| {
+                                out.push((i, res))
+                            });
+                        }
+                        out
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for handle in handles {
+                let out = handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e));
+                for (i, res) in out {
+                    results[i] = Some(res);
+                }
+            }
+        });
+    }
+
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_starts() {
+        assert_eq!(component_starts(b""), []);
+        assert_eq!(component_starts(b"/"), []);
+        assert_eq!(component_starts(b"a"), []);
+        assert_eq!(component_starts(b"/a"), [1]);
+        assert_eq!(component_starts(b"//a//b/"), [2, 5]);
+        assert_eq!(component_starts(b"a/./b/."), [2]);
+    }
+
+    #[test]
+    fn test_is_reusable() {
+        let empty = RealpathFlags::empty();
+        assert!(is_reusable(b"/", b"/a", empty));
+        assert!(is_reusable(b"/a/", b"/a/b", empty));
+        assert!(is_reusable(b"a/", b"a/../b", empty));
+        assert!(is_reusable(b"a/", b"a/b/..", empty));
+
+        assert!(!is_reusable(b"", b"/a", empty));
+        assert!(!is_reusable(b"/a/", b"/a/", empty));
+        assert!(!is_reusable(b"/a/", b"/a/.", empty));
+        assert!(!is_reusable(b"/a/", b"/a//b", empty));
+        assert!(!is_reusable(b"/a/", b"/ab/c", empty));
+        assert!(!is_reusable(b"/", b"//a", empty));
+
+        let logical = RealpathFlags::LOGICAL;
+        assert!(is_reusable(b"/a/", b"/a/b", logical));
+        assert!(is_reusable(b"/a/", b"/a/b/../c", logical));
+        assert!(!is_reusable(b"/", b"/../a", logical));
+        assert!(!is_reusable(b"/a/", b"/a/../b", logical));
+        assert!(!is_reusable(b"/a/", b"/a/b/../../c", logical));
+        assert!(!is_reusable(b"/a/", b"/a/b/..", logical));
+    }
+}
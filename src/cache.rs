@@ -0,0 +1,519 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CStr;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(target_family = "unix")]
+use std::os::unix::prelude::*;
+#[cfg(target_os = "wasi")]
+use std::os::wasi::prelude::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::watch::Watcher;
+use crate::FsBackend;
+
+#[derive(Debug)]
+enum Verdict {
+    Symlink(Box<[u8]>),
+    NotSymlink,
+}
+
+#[derive(Debug)]
+struct Entry {
+    verdict: Verdict,
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: BTreeMap<Box<[u8]>, Entry>,
+    /// The keys of `entries`, in the order they were inserted (entries that have since been
+    /// removed are skipped based on `seq`)
+    order: VecDeque<(u64, Box<[u8]>)>,
+    seq: u64,
+    /// Incremented every time a change is detected (or the cache is invalidated)
+    generation: u64,
+    /// The value of `generation` after the last call to [`Self::clear()`] (which also removes all
+    /// of the watches)
+    cleared: u64,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    watcher: Option<Watcher>,
+}
+
+impl Inner {
+    /// Prepare to look up `path` and add it to the cache. If it can be cached, this returns a
+    /// "ticket" that must be passed to [`Self::finish()`].
+    fn begin(&mut self, path: &[u8]) -> Option<u64> {
+        // The watch must be in place *before* the lookup; otherwise we could miss a change that
+        // happens in between
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.acquire(path).ok()?;
+        }
+
+        Some(self.generation)
+    }
+
+    /// Add `path` to the cache (if `verdict` is not `None`) after a call to [`Self::begin()`].
+    fn finish(&mut self, path: &[u8], ticket: u64, verdict: Option<Verdict>, capacity: usize) {
+        self.sync();
+
+        match verdict {
+            // If anything changed since the lookup started, the result may already be stale
+            Some(verdict) if ticket == self.generation => self.insert(path, verdict, capacity),
+            // The watch was already removed by `clear()`, and releasing it again could remove a
+            // watch acquired by a later lookup
+            _ if ticket < self.cleared => (),
+            _ => self.release(path),
+        }
+    }
+
+    fn insert(&mut self, path: &[u8], verdict: Verdict, capacity: usize) {
+        if capacity == 0 || self.entries.contains_key(path) {
+            self.release(path);
+            return;
+        }
+
+        // Evict the oldest entries until there's room
+        while self.entries.len() >= capacity {
+            match self.order.pop_front() {
+                Some((seq, key)) => {
+                    if self.entries.get(&key).map(|entry| entry.seq) == Some(seq) {
+                        self.remove(&key);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        // Invalidation leaves stale keys behind; don't let them accumulate
+        if self.order.len() >= capacity.saturating_mul(2) {
+            let entries = &self.entries;
+            self.order
+                .retain(|(seq, key)| entries.get(key).map(|entry| entry.seq) == Some(*seq));
+        }
+
+        self.seq += 1;
+        self.entries.insert(
+            path.into(),
+            Entry {
+                verdict,
+                seq: self.seq,
+            },
+        );
+        self.order.push_back((self.seq, path.into()));
+    }
+
+    fn remove(&mut self, path: &[u8]) {
+        if self.entries.remove(path).is_some() {
+            self.release(path);
+        }
+    }
+
+    #[inline]
+    fn release(&mut self, path: &[u8]) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.release(path);
+        }
+        let _ = path;
+    }
+
+    fn invalidate(&mut self, path: &[u8]) {
+        // Lookups that are still in progress may have seen the old state
+        self.generation += 1;
+
+        let keys = self
+            .entries
+            .range::<[u8], _>((Bound::Included(path), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(path))
+            .filter(|key| key.len() == path.len() || key[path.len()] == b'/')
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.cleared = self.generation;
+        self.entries.clear();
+        self.order.clear();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.clear();
+        }
+    }
+
+    /// Process any pending change notifications.
+    #[inline]
+    fn sync(&mut self) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(watcher) = self.watcher.as_mut() {
+            let changes = watcher.read_events();
+            if !changes.any {
+                return;
+            }
+
+            if changes.overflow {
+                self.clear();
+            } else {
+                for path in changes.paths {
+                    if path == b"/" {
+                        self.clear();
+                    } else {
+                        self.invalidate(&path);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A cache of symbolic link lookups, which can be shared between calls to
+/// [`RealpathBuilder::realpath()`] (see [`RealpathBuilder::cache()`]).
+///
+/// For every absolute path that is checked during resolution, the cache records either the
+/// contents of the symbolic link, or the fact that the path is not a symbolic link. Later lookups
+/// of the same path (for example, shared prefixes of many paths under the same tree) are answered
+/// from the cache without calling `readlink()`. Relative paths (including all paths resolved with
+/// [`RealpathFlags::IN_ROOT`]) are never cached, and errors (for example, `ENOENT`) are not
+/// cached either.
+///
+/// A cache created with [`Self::new()`] is never updated automatically. If the filesystem
+/// changes, call [`Self::invalidate()`] or [`Self::clear()`]; otherwise, resolution may produce
+/// stale results. (On Linux and Android, [`Self::with_inotify()`] can do this automatically.) A
+/// cache should only be used with a single [`FsBackend`] (or only with the OS's filesystem).
+///
+/// The cache holds at most `capacity` entries; when it is full, the oldest entries are evicted
+/// first. It can safely be shared between threads.
+///
+/// [`RealpathBuilder::realpath()`]: crate::RealpathBuilder::realpath
+/// [`RealpathBuilder::cache()`]: crate::RealpathBuilder::cache
+/// [`RealpathFlags::IN_ROOT`]: crate::RealpathFlags::IN_ROOT
+#[derive(Debug)]
+pub struct ResolveCache {
+    inner: Mutex<Inner>,
+    capacity: usize,
+}
+
+impl ResolveCache {
+    /// Create a new cache that holds at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            capacity,
+        }
+    }
+
+    /// Create a new cache that holds at most `capacity` entries, and that uses inotify to remove
+    /// entries automatically when the filesystem changes.
+    ///
+    /// Before each path is cached, its parent directory is watched for `IN_CREATE`, `IN_DELETE`,
+    /// `IN_MOVE`, and `IN_ATTRIB` events (as well as the directory itself being moved or deleted).
+    /// Pending events are processed at the start of every resolution, and any entries that may
+    /// have been affected are removed. If the event queue overflows, the whole cache is cleared;
+    /// and if a directory can't be watched (for example, because the inotify watch limit has been
+    /// reached), the paths inside it are simply not cached.
+    ///
+    /// Note that inotify does not report mounts and unmounts, so [`Self::invalidate()`] must still
+    /// be called manually if a filesystem is mounted over a cached path. The cache should only be
+    /// used with the OS's filesystem (i.e. not with [`RealpathBuilder::backend()`]).
+    ///
+    /// [`RealpathBuilder::backend()`]: crate::RealpathBuilder::backend
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn with_inotify(capacity: usize) -> std::io::Result<Self> {
+        let watcher = Watcher::new().map_err(std::io::Error::from_raw_os_error)?;
+
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                watcher: Some(watcher),
+                ..Inner::default()
+            }),
+            capacity,
+        })
+    }
+
+    /// Get the maximum number of entries that the cache can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the number of entries currently in the cache.
+    pub fn len(&self) -> usize {
+        self.lock_synced().entries.len()
+    }
+
+    /// Check whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove the entry for `path`, and the entries for all paths beneath it.
+    ///
+    /// `path` should be an absolute path as it would be seen during resolution (i.e. with all of
+    /// its parent directories already resolved). For example, if `/a/b` is changed from a
+    /// directory to a symbolic link, call `cache.invalidate("/a/b")`; this also removes entries
+    /// for paths like `/a/b/c` (which were looked up inside the old directory). Trailing slashes
+    /// are ignored, and invalidating `/` clears the entire cache.
+    pub fn invalidate<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref().as_os_str().as_bytes();
+        let path = match path.iter().rposition(|&ch| ch != b'/') {
+            Some(i) => &path[..=i],
+            None if path.is_empty() => return,
+            None => return self.clear(),
+        };
+
+        self.lock_synced().invalidate(path);
+    }
+
+    /// Remove all entries from the cache.
+    pub fn clear(&self) {
+        self.lock_synced().clear();
+    }
+
+    /// Process any pending change notifications (only relevant for [`Self::with_inotify()`]).
+    #[inline]
+    pub(crate) fn sync(&self) {
+        self.lock().sync();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // The cache is always left in a consistent state, so poisoning doesn't matter
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_synced(&self) -> MutexGuard<'_, Inner> {
+        let mut inner = self.lock();
+        inner.sync();
+        inner
+    }
+
+    /// If `path` is in the cache, copy the contents of the symbolic link into `buf` as
+    /// `readlink()` would.
+    fn readlink(&self, path: &[u8], buf: &mut [u8]) -> Option<Result<usize, i32>> {
+        Some(match self.lock().entries.get(path)?.verdict {
+            Verdict::Symlink(ref target) => {
+                let n = target.len().min(buf.len());
+                buf[..n].copy_from_slice(&target[..n]);
+                Ok(n)
+            }
+            Verdict::NotSymlink => Err(libc::EINVAL),
+        })
+    }
+
+    fn begin(&self, path: &[u8]) -> Option<u64> {
+        self.lock().begin(path)
+    }
+
+    fn finish(&self, path: &[u8], ticket: u64, verdict: Option<Verdict>) {
+        self.lock().finish(path, ticket, verdict, self.capacity);
+    }
+
+    #[cfg(test)]
+    fn insert(&self, path: &[u8], verdict: Verdict) {
+        if let Some(ticket) = self.begin(path) {
+            self.finish(path, ticket, Some(verdict));
+        }
+    }
+}
+
+/// A backend that consults a [`ResolveCache`] before reading symbolic links with another backend.
+pub(crate) struct CachedBackend<'a, B: ?Sized> {
+    pub cache: &'a ResolveCache,
+    pub inner: &'a B,
+}
+
+impl<B: FsBackend + ?Sized> FsBackend for CachedBackend<'_, B> {
+    fn readlink(&self, path: &CStr, buf: &mut [u8]) -> Result<usize, i32> {
+        let key = path.to_bytes();
+        if !key.starts_with(b"/") {
+            return self.inner.readlink(path, buf);
+        }
+
+        match self.cache.readlink(key, buf) {
+            Some(res) => res,
+
+            None => {
+                let ticket = match self.cache.begin(key) {
+                    Some(ticket) => ticket,
+                    None => return self.inner.readlink(path, buf),
+                };
+
+                let res = self.inner.readlink(path, buf);
+                let verdict = match res {
+                    // If the contents were truncated, we can't cache them
+                    Ok(n) if n < buf.len() => Some(Verdict::Symlink(buf[..n].into())),
+                    Err(libc::EINVAL) => Some(Verdict::NotSymlink),
+                    _ => None,
+                };
+                self.cache.finish(key, ticket, verdict);
+                res
+            }
+        }
+    }
+
+    #[inline]
+    fn check_isdir(&self, path: &CStr) -> Result<(), i32> {
+        self.inner.check_isdir(path)
+    }
+
+    #[inline]
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        self.inner.getcwd(buf)
+    }
+
+    #[inline]
+    fn getcwd_physical(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        self.inner.getcwd_physical(buf)
+    }
+
+    #[inline]
+    fn device(&self, path: &CStr) -> Result<u64, i32> {
+        self.inner.device(path)
+    }
+
+    #[inline]
+    fn file_id(&self, path: &CStr) -> Result<(u64, u64), i32> {
+        self.inner.file_id(path)
+    }
+
+    #[inline]
+    fn is_procfs(&self, path: &CStr) -> Result<bool, i32> {
+        self.inner.is_procfs(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(cache: &ResolveCache) -> Vec<Vec<u8>> {
+        cache
+            .lock()
+            .entries
+            .keys()
+            .map(|key| key.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = ResolveCache::new(100);
+        for &path in [
+            &b"/a"[..],
+            b"/a/b",
+            b"/a/b/c",
+            b"/a/bc",
+            b"/a/b.",
+            b"/ab",
+            b"//a",
+        ]
+        .iter()
+        {
+            cache.insert(path, Verdict::NotSymlink);
+        }
+        assert_eq!(cache.len(), 7);
+
+        cache.invalidate("/a/b/");
+        assert_eq!(
+            keys(&cache),
+            [&b"//a"[..], b"/a", b"/a/b.", b"/a/bc", b"/ab"]
+        );
+
+        cache.invalidate("a");
+        cache.invalidate("");
+        assert_eq!(cache.len(), 5);
+
+        cache.invalidate("/a");
+        assert_eq!(keys(&cache), [&b"//a"[..], b"/ab"]);
+
+        cache.invalidate("//");
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = ResolveCache::new(3);
+        assert_eq!(cache.capacity(), 3);
+
+        cache.insert(b"/a", Verdict::NotSymlink);
+        cache.insert(b"/b", Verdict::NotSymlink);
+        cache.insert(b"/c", Verdict::NotSymlink);
+        cache.insert(b"/a", Verdict::NotSymlink);
+        assert_eq!(cache.len(), 3);
+
+        cache.insert(b"/d", Verdict::NotSymlink);
+        assert_eq!(keys(&cache), [b"/b", b"/c", b"/d"]);
+
+        // Stale keys are skipped when evicting
+        cache.invalidate("/c");
+        cache.insert(b"/c", Verdict::NotSymlink);
+        cache.insert(b"/e", Verdict::NotSymlink);
+        assert_eq!(keys(&cache), [b"/c", b"/d", b"/e"]);
+        cache.insert(b"/f", Verdict::NotSymlink);
+        assert_eq!(keys(&cache), [b"/c", b"/e", b"/f"]);
+
+        // Stale keys don't accumulate
+        for _ in 0..100 {
+            cache.insert(b"/g", Verdict::NotSymlink);
+            cache.invalidate("/g");
+        }
+        assert!(cache.lock().order.len() <= 6);
+
+        let cache = ResolveCache::new(0);
+        cache.insert(b"/a", Verdict::NotSymlink);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_invalidate_in_flight() {
+        let cache = ResolveCache::new(100);
+
+        let ticket = cache.begin(b"/a/b").unwrap();
+        cache.invalidate("/a");
+        cache.finish(b"/a/b", ticket, Some(Verdict::NotSymlink));
+        assert!(cache.is_empty());
+
+        let ticket = cache.begin(b"/a/b").unwrap();
+        cache.clear();
+        cache.finish(b"/a/b", ticket, Some(Verdict::NotSymlink));
+        assert!(cache.is_empty());
+
+        let ticket = cache.begin(b"/a/b").unwrap();
+        cache.finish(b"/a/b", ticket, Some(Verdict::NotSymlink));
+        assert_eq!(keys(&cache), [b"/a/b"]);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_clear_in_flight_inotify() {
+        let tmpdir =
+            std::env::temp_dir().join(format!("realpath-ext-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmpdir);
+        std::fs::create_dir(&tmpdir).unwrap();
+        let path = tmpdir.join("a");
+        let path = path.as_os_str().as_bytes();
+
+        let cache = ResolveCache::with_inotify(100).unwrap();
+        let stale = cache.begin(path).unwrap();
+        cache.clear();
+        let ticket = cache.begin(path).unwrap();
+
+        // The stale lookup must not be cached, or remove the watch for the new one
+        cache.finish(path, stale, Some(Verdict::NotSymlink));
+        assert!(cache.is_empty());
+        cache.finish(path, ticket, Some(Verdict::NotSymlink));
+        assert_eq!(keys(&cache), [path]);
+
+        std::fs::create_dir(tmpdir.join("a")).unwrap();
+        cache.sync();
+        assert!(cache.is_empty());
+
+        std::fs::remove_dir_all(&tmpdir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+/// An error that occurred while canonicalizing a path.
+///
+/// This holds the OS error code that resolution failed with (see [`realpath_raw()`] for the
+/// possible errors), along with information about where in the path it failed (if the failure
+/// was caused by a specific component):
+///
+/// - [`Self::prefix()`]: The part of the path that had been resolved before the component that
+///   failed. This may be relative to the base directory (`""` refers to the base directory
+///   itself), or relative to the root directory with [`RealpathFlags::IN_ROOT`].
+/// - [`Self::component()`]: The component that failed (`..` or `/` if resolution failed while
+///   going up a directory or returning to the root).
+/// - [`Self::from_symlink()`]: Whether the component came from the target of a symbolic link
+///   (instead of the original path).
+/// - [`Self::symlink_cycle()`]: If resolution failed with `ELOOP` because symbolic links form a
+///   loop, the links in the loop (only with
+///   [`RealpathBuilder::detect_symlink_cycles()`](crate::RealpathBuilder::detect_symlink_cycles)).
+///
+/// This information is only recorded if the `std` feature is enabled.
+///
+/// Example usage:
+///
+/// ```
+/// # use realpath_ext::{realpath, RealpathFlags};
+/// let err = realpath("/dev/null/a/b", RealpathFlags::empty()).unwrap_err();
+/// assert_eq!(err.errno(), libc::ENOTDIR);
+/// assert_eq!(err.prefix(), Some(&b"/dev/null"[..]));
+/// assert_eq!(err.component(), Some(&b"a"[..]));
+/// assert!(!err.from_symlink());
+/// ```
+///
+/// [`realpath_raw()`]: crate::realpath_raw
+/// [`RealpathFlags::IN_ROOT`]: crate::RealpathFlags::IN_ROOT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RealpathError {
+    errno: i32,
+    #[cfg(feature = "std")]
+    context: Option<Box<Context>>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Context {
+    prefix: Vec<u8>,
+    component: Vec<u8>,
+    from_symlink: bool,
+    /// The paths to the symbolic links that form a loop (if any)
+    cycle: Vec<Vec<u8>>,
+}
+
+impl RealpathError {
+    /// Create an error from an OS error code (with no information about where it occurred).
+    #[inline]
+    pub const fn from_errno(errno: i32) -> Self {
+        Self {
+            errno,
+            #[cfg(feature = "std")]
+            context: None,
+        }
+    }
+
+    /// Record the failing component described by `context`.
+    #[cfg(feature = "std")]
+    pub(crate) fn with_context(mut self, context: crate::resolver::ErrorContext<'_>) -> Self {
+        self.context = Some(Box::new(Context {
+            prefix: context.prefix.into(),
+            component: context.component.into(),
+            from_symlink: context.from_symlink,
+            cycle: Vec::new(),
+        }));
+        self
+    }
+
+    /// Record the symbolic links that form the loop that caused this error.
+    #[cfg(feature = "std")]
+    pub(crate) fn with_cycle(mut self, cycle: &[Vec<u8>]) -> Self {
+        // Symbolic links are only followed while resolving a component
+        debug_assert!(self.context.is_some());
+        if let Some(context) = self.context.as_mut() {
+            context.cycle = cycle.to_vec();
+        }
+        self
+    }
+
+    /// Get the OS error code.
+    #[inline]
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    /// Get the OS error code (always `Some`).
+    ///
+    /// This is the same as [`Self::errno()`]; it is provided to ease migrating from
+    /// [`std::io::Error`].
+    #[inline]
+    pub fn raw_os_error(&self) -> Option<i32> {
+        Some(self.errno)
+    }
+
+    /// Get the corresponding [`std::io::ErrorKind`].
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn kind(&self) -> std::io::ErrorKind {
+        std::io::Error::from_raw_os_error(self.errno).kind()
+    }
+
+    /// Get the part of the path that had been resolved before the component that failed.
+    ///
+    /// This returns `None` if the failure was not caused by a specific component (or the `std`
+    /// feature is disabled).
+    #[inline]
+    pub fn prefix(&self) -> Option<&[u8]> {
+        #[cfg(feature = "std")]
+        return self.context.as_ref().map(|context| &context.prefix[..]);
+        #[cfg(not(feature = "std"))]
+        return None;
+    }
+
+    /// Get the component that failed.
+    ///
+    /// This returns `None` if the failure was not caused by a specific component (or the `std`
+    /// feature is disabled).
+    #[inline]
+    pub fn component(&self) -> Option<&[u8]> {
+        #[cfg(feature = "std")]
+        return self.context.as_ref().map(|context| &context.component[..]);
+        #[cfg(not(feature = "std"))]
+        return None;
+    }
+
+    /// Check whether the component that failed came from the target of a symbolic link.
+    #[inline]
+    pub fn from_symlink(&self) -> bool {
+        #[cfg(feature = "std")]
+        return matches!(self.context.as_deref(), Some(context) if context.from_symlink);
+        #[cfg(not(feature = "std"))]
+        return false;
+    }
+
+    /// If resolution failed with `ELOOP` because of a loop of symbolic links, get the paths to
+    /// the links that form the loop.
+    ///
+    /// The links are listed in the order they were followed, starting and ending with the same
+    /// link (for example, `a`, `b`, `c`, `a` if `a` points to `b`, which points to `c`, which
+    /// points back to `a`). Like [`Self::prefix()`], the paths may be relative to the base
+    /// directory or the root directory.
+    ///
+    /// Loops are only looked for if enabled with
+    /// [`RealpathBuilder::detect_symlink_cycles()`](crate::RealpathBuilder::detect_symlink_cycles).
+    /// A loop is detected if a symbolic link is followed again with the same path left to resolve
+    /// after it (so resolution would never finish). Links are compared by their device and inode
+    /// numbers, so a loop that passes through a bind mount or a hard link to a directory is found
+    /// too (unless the backend can't report them; see
+    /// [`FsBackend::file_id()`](crate::FsBackend::file_id)). This returns
+    /// `None` if there is no loop (for example, if the path just contains too many symbolic
+    /// links), if the loop is too long to be detected within the limits on resolution, or if
+    /// detection was not enabled.
+    #[inline]
+    pub fn symlink_cycle(&self) -> Option<impl Iterator<Item = &[u8]> + '_> {
+        #[cfg(feature = "std")]
+        return self
+            .context
+            .as_ref()
+            .filter(|context| !context.cycle.is_empty())
+            .map(|context| context.cycle.iter().map(|link| &link[..]));
+        #[cfg(not(feature = "std"))]
+        return None::<core::iter::Empty<&[u8]>>;
+    }
+}
+
+impl From<i32> for RealpathError {
+    #[inline]
+    fn from(errno: i32) -> Self {
+        Self::from_errno(errno)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for RealpathError {
+    #[inline]
+    fn from(err: std::io::Error) -> Self {
+        Self::from_errno(err.raw_os_error().unwrap_or(libc::EIO))
+    }
+}
+
+/// The information about where the error occurred is lost in the conversion.
+#[cfg(feature = "std")]
+impl From<RealpathError> for std::io::Error {
+    #[inline]
+    fn from(err: RealpathError) -> Self {
+        Self::from_raw_os_error(err.errno)
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Display for RealpathError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        let err = std::io::Error::from_raw_os_error(self.errno);
+        match self.context.as_ref() {
+            Some(context) => {
+                write!(f, "{:?}", std::ffi::OsStr::from_bytes(&context.component))?;
+                if !context.prefix.is_empty() {
+                    write!(f, " in {:?}", std::ffi::OsStr::from_bytes(&context.prefix))?;
+                }
+                if context.from_symlink {
+                    f.write_str(" (from a symbolic link)")?;
+                }
+                write!(f, ": {}", err)?;
+                for (i, link) in context.cycle.iter().enumerate() {
+                    let sep = if i == 0 { " (loop: " } else { " -> " };
+                    write!(f, "{}{:?}", sep, std::ffi::OsStr::from_bytes(link))?;
+                }
+                if !context.cycle.is_empty() {
+                    f.write_str(")")?;
+                }
+                Ok(())
+            }
+            None => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RealpathError {}
@@ -1,10 +1,30 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+mod backend;
+#[cfg(feature = "std")]
+mod batch;
+#[cfg(feature = "std")]
+mod cache;
+mod error;
+#[cfg(feature = "std")]
+mod memfs;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+mod open;
+mod resolver;
 mod slicevec;
 mod util;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+mod watch;
 
+pub use backend::{FsBackend, LibcBackend};
+#[cfg(feature = "std")]
+pub use cache::ResolveCache;
+pub use error::RealpathError;
+#[cfg(feature = "std")]
+pub use memfs::MemoryFs;
+pub use resolver::{ResolveStep, Resolver};
 use slicevec::SliceVec;
-use util::{ComponentIter, ComponentStack, SymlinkCounter};
+use util::ComponentIter;
 
 #[cfg(target_family = "unix")]
 const PATH_MAX: usize = libc::PATH_MAX as usize;
@@ -100,7 +120,211 @@ bitflags::bitflags! {
         /// Note that if this option is passed, the returned path may not refer to the correct file!
         /// Certain combinations of `..` and/or symbolic links can cause this.
         const IGNORE_SYMLINKS = 0x04;
+        /// Treat the base directory (the current working directory, or the `dirfd` passed to
+        /// [`realpathat()`]) as the root directory, similar to `chroot()` or the `RESOLVE_IN_ROOT`
+        /// flag to Linux's `openat2()`.
+        ///
+        /// Absolute paths (including the targets of absolute symbolic links) are resolved
+        /// relative to the base directory, and `..` at the root stays at the root. The returned
+        /// path is relative to the root (for example, `/a/b` refers to `<root>/a/b`).
+        ///
+        /// Note that this is emulated in userspace; if a directory that has already been resolved
+        /// is concurrently moved outside the root, the result may refer to a file outside it.
+        const IN_ROOT = 0x08;
+        /// Fail with `EXDEV` if resolution would leave the base directory (the current working
+        /// directory, or the `dirfd` passed to [`realpathat()`]), similar to the
+        /// `RESOLVE_BENEATH` flag to Linux's `openat2()`.
+        ///
+        /// This includes absolute paths, absolute symbolic links, and `..` components that would
+        /// move above the base directory. (`..` components that stay within it are allowed.)
+        ///
+        /// This flag cannot be combined with [`Self::IN_ROOT`]; resolution will fail with
+        /// `EINVAL` if both are passed. Note that like [`Self::IN_ROOT`], this is emulated in
+        /// userspace.
+        const BENEATH = 0x10;
+        /// Fail with `EXDEV` if any component of the path (including components reached by
+        /// following symbolic links or `..`) is on a different filesystem than the starting
+        /// directory: the root directory for an absolute path (unless [`Self::IN_ROOT`] is
+        /// passed), or the base directory (the current working directory, or the `dirfd` passed
+        /// to [`realpathat()`]) for a relative path.
+        ///
+        /// This compares the `st_dev` values returned by `stat()`, so it requires an extra system
+        /// call for every component. Unlike the `RESOLVE_NO_XDEV` flag to Linux's `openat2()`, it
+        /// will not detect bind mounts of the same filesystem.
+        const NO_XDEV = 0x20;
+        /// Fail with `ELOOP` if any component of the path is a symbolic link, similar to the
+        /// `RESOLVE_NO_SYMLINKS` flag to Linux's `openat2()`.
+        ///
+        /// Unlike [`Self::IGNORE_SYMLINKS`], this guarantees that if resolution succeeds, the
+        /// path did not contain any symbolic links (at the time it was resolved). If both flags
+        /// are passed, this one takes precedence.
+        const NO_SYMLINKS = 0x40;
+        /// Fail with `ELOOP` if a procfs "magic link" is encountered (for example,
+        /// `/proc/self/fd/<N>` or `/proc/<PID>/root`), similar to the `RESOLVE_NO_MAGICLINKS` flag
+        /// to Linux's `openat2()`.
+        ///
+        /// Regardless of this flag, resolution fails with `ENXIO` if a magic link is encountered
+        /// whose target is not a path at all (for example, `pipe:[1234]` or
+        /// `anon_inode:[eventfd]`).
+        ///
+        /// Magic links are only detected on Linux and Android; on other OSes this flag is ignored.
+        const NO_MAGICLINKS = 0x80;
+        /// Handle `..` components in the given path lexically (by removing the preceding
+        /// component) before following symbolic links, like the `-L` option to GNU `realpath`.
+        ///
+        /// For example, if `/a/b` is a symbolic link to `/c/d`, then `/a/b/..` resolves to `/a`
+        /// (instead of `/c`), and `/a/missing/..` resolves to `/a` even though `/a/missing` does
+        /// not exist. Components that are removed this way are never looked up. `..` components
+        /// in the targets of symbolic links are still handled normally, as are `..` components
+        /// at the start of a relative path (which refer to the parent of the base directory).
+        ///
+        /// With this flag, a small part of the temporary buffer (one byte for every 16 bytes of
+        /// the given path) is used to record which components are removed.
+        const LOGICAL = 0x100;
+        /// Do not follow the last component of the given path if it is a symbolic link, like
+        /// `lstat()` or `O_NOFOLLOW`.
+        ///
+        /// Every other component is resolved normally, so the result is the canonicalized path of
+        /// the symbolic link itself rather than of its target (which is what is needed to remove
+        /// or rename the link, for example). As with `lstat()`, the last component is still
+        /// followed if the path ends with a slash (or `/.`).
+        ///
+        /// If [`Self::NO_SYMLINKS`] is also passed, the last component is allowed to be a
+        /// symbolic link (like `RESOLVE_NO_SYMLINKS` combined with `O_NOFOLLOW` for Linux's
+        /// `openat2()`).
+        const NOFOLLOW_LAST = 0x200;
+    }
+}
+
+/// An observer that is notified of each step taken while resolving a path.
+///
+/// This can be used to trace how a path was resolved (for example, when debugging unexpected
+/// results). Pass an observer to [`RealpathRawBuilder::realpath_raw_observed()`],
+/// [`RealpathBuilder::realpath_observed()`], or [`Resolver::step_observed()`]; all of the methods
+/// have empty default implementations, so implementors only need to override the ones they are
+/// interested in.
+///
+/// The paths passed to these methods are the partially resolved path at that point. They may be
+/// relative (to the current working directory or base directory) until the very end of
+/// resolution, and with [`RealpathFlags::IN_ROOT`] they are relative to the root directory.
+///
+/// `()` implements this trait by ignoring all events; the non-observing functions use it, so
+/// observing costs nothing when it is not used.
+pub trait ResolveObserver {
+    /// A component that is not a symbolic link was appended; `path` is the resulting path.
+    #[inline]
+    fn component(&mut self, path: &[u8]) {
+        let _ = path;
+    }
+
+    /// A `..` component was applied; `path` is the resulting path.
+    #[inline]
+    fn parent(&mut self, path: &[u8]) {
+        let _ = path;
+    }
+
+    /// Resolution restarted at the root directory (either because the path was absolute or
+    /// because of an absolute symbolic link); `path` is `/` or `//`.
+    #[inline]
+    fn root(&mut self, path: &[u8]) {
+        let _ = path;
+    }
+
+    /// The symbolic link at `path` was read, and its contents (`target`) will be resolved next.
+    #[inline]
+    fn symlink(&mut self, path: &[u8], target: &[u8]) {
+        let _ = (path, target);
+    }
+
+    /// The path to the current working directory (or the base directory) was fetched in order to
+    /// make the result absolute.
+    #[inline]
+    fn cwd(&mut self, cwd: &[u8]) {
+        let _ = cwd;
+    }
+
+    /// A component that could not be resolved (failing with the given `errno`) was appended
+    /// anyway because of [`RealpathFlags::ALLOW_MISSING`] or
+    /// [`RealpathFlags::ALLOW_LAST_MISSING`]; `path` is the resulting path.
+    #[inline]
+    fn missing(&mut self, path: &[u8], errno: i32) {
+        let _ = (path, errno);
+    }
+}
+
+impl ResolveObserver for () {}
+
+/// The result of canonicalizing a path, including which part of it is known to exist.
+///
+/// With [`RealpathFlags::ALLOW_MISSING`] or [`RealpathFlags::ALLOW_LAST_MISSING`], some
+/// components of the result may not exist (or may be inaccessible); they are appended to the
+/// result as-is. Once one component has been appended this way, none of the components after it
+/// can be looked up either, so the result is always made up of an existing prefix (the first
+/// `existing_len` bytes, which were verified component by component) followed by the components
+/// that were assumed. The components after `existing_len` were not verified, whether because they
+/// are missing or because they are inaccessible:
+///
+/// ```
+/// # use realpath_ext::{RealpathFlags, RealpathRawBuilder};
+/// let mut buf = [0; libc::PATH_MAX as usize];
+/// let res = RealpathRawBuilder::new()
+///     .flags(RealpathFlags::ALLOW_MISSING)
+///     .realpath_raw_resolved(b"/dev/missing/a", &mut buf)
+///     .unwrap();
+/// assert_eq!(&buf[..res.len], b"/dev/missing/a");
+/// assert_eq!(res.existing(&buf), b"/dev");
+/// assert!(res.assumed(&buf).eq([&b"missing"[..], b"a"]));
+/// ```
+///
+/// This is returned by [`RealpathRawBuilder::realpath_raw_resolved()`] and
+/// [`RealpathBuilder::realpath_resolved()`]. Note that the existing prefix is only known to have
+/// existed at the time the path was resolved.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct Resolved {
+    /// The length of the resolved path.
+    pub len: usize,
+    /// The length of the part of the resolved path that is known to exist (without a trailing
+    /// slash).
+    ///
+    /// This is equal to `len` if no components were assumed.
+    pub existing_len: usize,
+}
+
+impl Resolved {
+    /// Check whether every component of the resolved path was found to exist.
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.existing_len == self.len
+    }
+
+    /// Get the part of the resolved path in `path` that is known to exist.
+    ///
+    /// `path` must be the resolved path (or the buffer it was stored in).
+    #[inline]
+    pub fn existing<'b>(&self, path: &'b [u8]) -> &'b [u8] {
+        &path[..self.existing_len]
     }
+
+    /// Get an iterator over the components of the resolved path in `path` that were assumed
+    /// (because they were missing or inaccessible).
+    ///
+    /// `path` must be the resolved path (or the buffer it was stored in).
+    #[inline]
+    pub fn assumed<'b>(&self, path: &'b [u8]) -> impl Iterator<Item = &'b [u8]> + 'b {
+        path[self.existing_len..self.len]
+            .split(|&ch| ch == b'/')
+            .filter(|component| !component.is_empty())
+    }
+}
+
+/// A base directory set with [`RealpathBuilder::base_dir()`].
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct BaseDir {
+    dir: std::fs::File,
+    /// The canonicalized path to `dir`
+    path: Vec<u8>,
 }
 
 /// A "builder" that allows customizing options to `realpath_raw()`.
@@ -111,14 +335,25 @@ bitflags::bitflags! {
 pub struct RealpathBuilder {
     max_len: usize,
     flags: RealpathFlags,
+    dirfd: libc::c_int,
+    base: Option<std::sync::Arc<BaseDir>>,
+    root: Option<std::path::PathBuf>,
+    openat2: bool,
+    pwd: bool,
+    limits: util::Limits,
+    cycles: bool,
+    backend: Option<std::sync::Arc<dyn FsBackend + Send + Sync>>,
+    cache: Option<std::sync::Arc<ResolveCache>>,
 }
 
 #[cfg(feature = "std")]
 impl RealpathBuilder {
     /// Create a new "builder".
     ///
-    /// The returned builder has its `flags` empty. `max_size` will be set to 32768 on WASI, and
-    /// `PATH_MAX` on other OSes.
+    /// The returned builder has its `flags` empty, `base_dir_fd`, `base_dir`, `root_dir`,
+    /// `backend`, `cache`, `max_symlinks`, `max_components`, and `max_link_bytes` set to `None`,
+    /// and `use_openat2`, `use_pwd`, and `detect_symlink_cycles` set to `false`. `max_size` will
+    /// be set to 32768 on WASI, and `PATH_MAX` on other OSes.
     #[inline]
     pub fn new() -> Self {
         Self {
@@ -128,6 +363,15 @@ impl RealpathBuilder {
                 PATH_MAX
             },
             flags: RealpathFlags::empty(),
+            dirfd: libc::AT_FDCWD,
+            base: None,
+            root: None,
+            openat2: false,
+            pwd: false,
+            limits: util::Limits::default(),
+            cycles: false,
+            backend: None,
+            cache: None,
         }
     }
 
@@ -144,6 +388,60 @@ impl RealpathBuilder {
         self
     }
 
+    /// Set the maximum number of symbolic links that can be followed while resolving a path.
+    ///
+    /// If `max` is `None` (default), the system's `SYMLOOP_MAX` is used (see [`realpath_raw()`]).
+    /// Resolution fails with `ELOOP` if more symbolic links than this are encountered.
+    #[inline]
+    pub fn max_symlinks(&mut self, max: Option<u16>) -> &mut Self {
+        self.limits.symlinks = max;
+        self
+    }
+
+    /// Set the maximum number of components that can be processed while resolving a path.
+    ///
+    /// Every component of the path and of the targets of the symbolic links that are followed
+    /// counts toward this limit (including `..` and `/`, but not `.`). Most components take one
+    /// filesystem operation to resolve, so together with [`Self::max_symlinks()`] and
+    /// [`Self::max_link_bytes()`], this bounds the work done for untrusted paths regardless of
+    /// the system's limits.
+    ///
+    /// If `max` is `None` (default), there is no limit. Resolution fails with `ELOOP` if the
+    /// limit is exceeded.
+    #[inline]
+    pub fn max_components(&mut self, max: Option<usize>) -> &mut Self {
+        self.limits.components = max;
+        self
+    }
+
+    /// Set the maximum total length of the targets of the symbolic links that can be followed
+    /// while resolving a path.
+    ///
+    /// If `max` is `None` (default), there is no limit. Resolution fails with `ELOOP` if the
+    /// limit is exceeded.
+    #[inline]
+    pub fn max_link_bytes(&mut self, max: Option<usize>) -> &mut Self {
+        self.limits.link_bytes = max;
+        self
+    }
+
+    /// Set whether to look for loops of symbolic links.
+    ///
+    /// If `enable` is `true`, every symbolic link that is followed is recorded by its device and
+    /// inode numbers (see [`FsBackend::file_id()`]), along with the part of the path that is left
+    /// to resolve after it. If the same link is reached again with the same path left, resolution
+    /// would never finish, so it fails with `ELOOP` right away, and the error records the links
+    /// that form the loop (see [`RealpathError::symlink_cycle()`]).
+    ///
+    /// This costs an extra `lstat()` call and an allocation for every symbolic link that is
+    /// followed, so it is disabled by default (an `ELOOP` error then only says that one of the
+    /// limits above was reached).
+    #[inline]
+    pub fn detect_symlink_cycles(&mut self, enable: bool) -> &mut Self {
+        self.cycles = enable;
+        self
+    }
+
     /// Set the flags used to modify path resolution.
     ///
     /// See [`RealpathFlags`] for more information.
@@ -153,44 +451,698 @@ impl RealpathBuilder {
         self
     }
 
+    /// Set the directory that relative paths are resolved against.
+    ///
+    /// If `fd` is `None` (default), relative paths are resolved against the current working
+    /// directory. Otherwise, they are resolved against the directory referred to by `fd`; see
+    /// [`realpathat()`] for more information.
+    ///
+    /// The file descriptor is not closed when the builder is dropped.
+    #[inline]
+    pub fn base_dir_fd(&mut self, fd: Option<libc::c_int>) -> &mut Self {
+        self.dirfd = fd.unwrap_or(libc::AT_FDCWD);
+        self
+    }
+
+    /// Set the directory that relative paths are resolved against by path.
+    ///
+    /// If `dir` is not `None`, it is canonicalized (if it is relative, against the current working
+    /// directory) and opened immediately, and relative paths are resolved against the opened
+    /// directory. The canonicalized path is used as the start of the returned paths, so unlike
+    /// [`Self::base_dir_fd()`], `getcwd()` (or its equivalent) never has to be called. (If the
+    /// directory is moved later, the returned paths will still start with its old path.) For the
+    /// same reason, the `openat2()` fast path (see [`Self::use_openat2()`]) is not used for
+    /// relative paths when this is set.
+    ///
+    /// This takes precedence over [`Self::base_dir_fd()`]. Pass `None` (default) to go back to
+    /// using that setting.
+    ///
+    /// # Errors
+    ///
+    /// This fails if `dir` could not be canonicalized or opened (for example, `ENOTDIR` if it is
+    /// not a directory), in which case the builder is not modified.
+    pub fn base_dir(&mut self, dir: Option<std::path::PathBuf>) -> std::io::Result<&mut Self> {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        self.base = match dir {
+            Some(dir) => {
+                let path = realpath(dir, RealpathFlags::empty())?;
+                let dir = std::fs::File::open(&path)?;
+                if !dir.metadata()?.is_dir() {
+                    return Err(std::io::Error::from_raw_os_error(libc::ENOTDIR));
+                }
+
+                Some(std::sync::Arc::new(BaseDir {
+                    dir,
+                    path: path.into_os_string().into_vec(),
+                }))
+            }
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Set a directory to resolve paths inside of, as if it were the root directory.
+    ///
+    /// If `root` is not `None`, it will be opened for every call to [`Self::realpath()`], and
+    /// paths will be resolved as if [`RealpathFlags::IN_ROOT`] had been passed with that directory
+    /// as the base directory. (If `root` is relative, it is resolved against the current working
+    /// directory.)
+    ///
+    /// To use an already-open directory as the root, pass [`RealpathFlags::IN_ROOT`] and set
+    /// [`Self::base_dir_fd()`] instead.
+    #[inline]
+    pub fn root_dir(&mut self, root: Option<std::path::PathBuf>) -> &mut Self {
+        self.root = root;
+        self
+    }
+
+    /// Set whether to try resolving paths with Linux's `openat2()` first.
+    ///
+    /// If `enable` is `true`, [`Self::realpath()`] will first open the path with `openat2()` and
+    /// `O_PATH`, then read the path to the opened file back from `/proc/self/fd`. This lets the
+    /// kernel do the resolution, instead of calling `readlink()` on every component. If that fails
+    /// for any reason (for example, `openat2()` is not supported, `/proc` is not mounted, or the
+    /// path does not exist), the normal algorithm is used instead (so errors are reported as
+    /// usual).
+    ///
+    /// The fast path is skipped for any flags that require handling in userspace (currently
+    /// everything except [`RealpathFlags::ALLOW_LAST_MISSING`], [`RealpathFlags::NO_SYMLINKS`],
+    /// [`RealpathFlags::NO_MAGICLINKS`], and [`RealpathFlags::BENEATH`]), and for paths starting
+    /// with exactly two slashes. It is also skipped if any of [`Self::max_symlinks()`],
+    /// [`Self::max_components()`], or [`Self::max_link_bytes()`] are set, since the kernel applies
+    /// its own limits. Note that symbolic links whose targets begin with exactly two slashes will
+    /// be treated as if they began with one.
+    ///
+    /// This has no effect on OSes other than Linux and Android.
+    #[inline]
+    pub fn use_openat2(&mut self, enable: bool) -> &mut Self {
+        self.openat2 = enable;
+        self
+    }
+
+    /// Set whether to take the path to the current working directory from `$PWD`.
+    ///
+    /// If `enable` is `true` and relative paths are resolved against the current working
+    /// directory, the path to it is taken from the `PWD` environment variable, as long as that is
+    /// absolute, contains no `.` or `..` components, and refers to the same directory as `.` (the
+    /// rule that `pwd -L` uses). Otherwise, `getcwd()` is used as usual. This preserves any
+    /// symbolic links in the path that the shell used to reach the current directory. (For
+    /// example, if `/home/me` is a symbolic link to `/data/home/me` and the shell did
+    /// `cd /home/me`, `a` resolves to `/home/me/a` instead of `/data/home/me/a`.) `$PWD` is
+    /// checked once at the start of every call.
+    ///
+    /// Leading `..` components in relative paths are only resolved against `$PWD` (so `../a`
+    /// resolves to `/home/a` in the example above) if [`RealpathFlags::LOGICAL`] is passed.
+    /// Otherwise, they are resolved by the OS as usual, and the path to the current directory is
+    /// taken from `getcwd()` when they are present (so `../a` resolves to `/data/home/a`).
+    ///
+    /// The `openat2()` fast path (see [`Self::use_openat2()`]) is not used for relative paths
+    /// when this is enabled.
+    #[inline]
+    pub fn use_pwd(&mut self, enable: bool) -> &mut Self {
+        self.pwd = enable;
+        self
+    }
+
+    /// Set the filesystem backend used to resolve paths.
+    ///
+    /// If `backend` is `None` (default), the OS's filesystem is used (see [`LibcBackend`]).
+    /// Otherwise, all filesystem operations are performed with the given backend, and relative
+    /// paths are resolved against its base directory. In that case, [`Self::base_dir_fd()`],
+    /// [`Self::base_dir()`], [`Self::root_dir()`], [`Self::use_openat2()`], and
+    /// [`Self::use_pwd()`] are ignored (pass [`RealpathFlags::IN_ROOT`] to treat the backend's base
+    /// directory as the root), and [`Self::realpath_open()`] fails with `EINVAL`.
+    ///
+    /// See [`FsBackend`] for more information.
+    #[inline]
+    pub fn backend(
+        &mut self,
+        backend: Option<std::sync::Arc<dyn FsBackend + Send + Sync>>,
+    ) -> &mut Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Set a cache of symbolic link lookups to use (and update) while resolving paths.
+    ///
+    /// If `cache` is not `None`, the given cache is consulted before reading any symbolic link
+    /// with an absolute path, and updated with the results. This can significantly speed up
+    /// resolving many paths under the same directories. The same cache can be shared between
+    /// several builders (or threads); see [`ResolveCache`] for more information, including how to
+    /// invalidate it.
+    ///
+    /// The cache is not used by the `openat2()` fast path (see [`Self::use_openat2()`]), or by
+    /// [`Self::realpath_open()`].
+    #[inline]
+    pub fn cache(&mut self, cache: Option<std::sync::Arc<ResolveCache>>) -> &mut Self {
+        self.cache = cache;
+        self
+    }
+
     /// Canonicalize the given path.
+    ///
+    /// If [`RealpathFlags::IN_ROOT`] was passed or [`Self::root_dir()`] was set, the returned path
+    /// is relative to the root directory; see [`Self::realpath_in_root()`] to also get the path
+    /// on the host.
     pub fn realpath<P: AsRef<std::path::Path>>(
         &self,
         path: P,
-    ) -> std::io::Result<std::path::PathBuf> {
+    ) -> Result<std::path::PathBuf, RealpathError> {
+        self.realpath_maybe_observed(path.as_ref(), None)
+            .map(|(path, _)| path)
+    }
+
+    /// Canonicalize the given path, also returning which part of the result is known to exist.
+    ///
+    /// This is like [`Self::realpath()`], but the returned [`Resolved`] records where the part
+    /// of the result that was verified to exist ends (see [`Resolved::existing()`] and
+    /// [`Resolved::assumed()`], which should be passed the bytes of the returned path). This is
+    /// only useful with [`RealpathFlags::ALLOW_MISSING`] or [`RealpathFlags::ALLOW_LAST_MISSING`];
+    /// otherwise every component of the result exists.
+    pub fn realpath_resolved<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<(std::path::PathBuf, Resolved), RealpathError> {
+        self.realpath_maybe_observed(path.as_ref(), None)
+    }
+
+    /// Canonicalize the given path, notifying `observer` of each step.
+    ///
+    /// See [`ResolveObserver`] for more information. Note that if resolution has to be retried
+    /// with a larger buffer (see [`Self::max_len()`]), `observer` will be notified of the same
+    /// steps again. The `openat2()` fast path (see [`Self::use_openat2()`]) is never used by this
+    /// method.
+    pub fn realpath_observed<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        observer: &mut dyn ResolveObserver,
+    ) -> Result<std::path::PathBuf, RealpathError> {
+        self.realpath_maybe_observed(path.as_ref(), Some(observer))
+            .map(|(path, _)| path)
+    }
+
+    /// Canonicalize all of the given paths, returning the results in the same order.
+    ///
+    /// This is equivalent to calling [`Self::realpath()`] on each path, but it is faster when many
+    /// of the paths have a common prefix (for example, thousands of files under a few
+    /// directories). The paths are resolved in sorted order, and the resolved form of the
+    /// directories that consecutive paths have in common is reused instead of being resolved
+    /// again. The `openat2()` fast path (see [`Self::use_openat2()`]) is never used by this
+    /// method.
+    pub fn realpath_many<I, P>(&self, paths: I) -> Vec<Result<std::path::PathBuf, RealpathError>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<std::path::Path>,
+    {
+        self.realpath_many_threads(paths, 1)
+    }
+
+    /// Like [`Self::realpath_many()`], but spreads the work across up to `threads` threads (or
+    /// the number returned by [`std::thread::available_parallelism()`] if `threads` is 0).
+    ///
+    /// Each thread repeatedly takes a run of consecutive paths (in sorted order, so shared
+    /// prefixes are still reused) and resolves it with its own buffers. This is most useful when
+    /// filesystem operations are slow (for example, on network filesystems).
+    pub fn realpath_many_parallel<I, P>(
+        &self,
+        paths: I,
+        threads: usize,
+    ) -> Vec<Result<std::path::PathBuf, RealpathError>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<std::path::Path>,
+    {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        self.realpath_many_threads(paths, threads)
+    }
+
+    fn realpath_many_threads<I, P>(
+        &self,
+        paths: I,
+        threads: usize,
+    ) -> Vec<Result<std::path::PathBuf, RealpathError>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<std::path::Path>,
+    {
         #[cfg(target_family = "unix")]
         use std::os::unix::prelude::*;
         #[cfg(target_os = "wasi")]
         use std::os::wasi::prelude::*;
 
-        let len = PATH_MAX.min(self.max_len);
-        let mut buf = vec![0; len];
-        let mut tmp = vec![0; len + 100];
+        let owned = paths.into_iter().collect::<Vec<_>>();
+        let paths = owned.iter().map(|path| path.as_ref()).collect::<Vec<_>>();
+
+        let root = match self.root.as_ref().filter(|_| self.backend.is_none()) {
+            Some(root) => match std::fs::File::open(root) {
+                Ok(root) => Some(root),
+                Err(e) => {
+                    let eno = e.raw_os_error().unwrap_or(libc::EIO);
+                    return paths
+                        .iter()
+                        .map(|_| Err(RealpathError::from_errno(eno)))
+                        .collect();
+                }
+            },
+            None => None,
+        };
+
+        let (dirfd, flags) = match root.as_ref() {
+            Some(root) => (root.as_raw_fd(), self.flags | RealpathFlags::IN_ROOT),
+            None => (self.base_fd(), self.flags),
+        };
+
+        self.with_backend(dirfd, flags, |backend| {
+            batch::resolve_many(
+                backend,
+                &paths,
+                flags,
+                self.limits,
+                self.cycles,
+                self.max_len,
+                threads,
+            )
+        })
+    }
+
+    fn realpath_maybe_observed(
+        &self,
+        path: &std::path::Path,
+        observer: Option<&mut dyn ResolveObserver>,
+    ) -> Result<(std::path::PathBuf, Resolved), RealpathError> {
+        if let Some(root) = self.root.as_ref().filter(|_| self.backend.is_none()) {
+            #[cfg(target_family = "unix")]
+            use std::os::unix::prelude::*;
+            #[cfg(target_os = "wasi")]
+            use std::os::wasi::prelude::*;
+
+            let root = std::fs::File::open(root)?;
+            self.realpath_at(
+                root.as_raw_fd(),
+                path,
+                self.flags | RealpathFlags::IN_ROOT,
+                observer,
+            )
+        } else {
+            self.realpath_at(self.base_fd(), path, self.flags, observer)
+        }
+    }
+
+    /// Canonicalize the given path inside a root directory.
+    ///
+    /// This resolves `path` as if [`RealpathFlags::IN_ROOT`] had been passed (regardless of the
+    /// current `flags`), then returns a tuple of `(path_in_root, host_path)`. `path_in_root` is
+    /// the path relative to the root directory (as returned by [`Self::realpath()`]), and
+    /// `host_path` is the same path with the canonicalized path to the root directory prepended.
+    ///
+    /// The root directory is either the one set with [`Self::root_dir()`], the directory set with
+    /// [`Self::base_dir()`] or [`Self::base_dir_fd()`], or the current working directory (in that
+    /// order of precedence).
+    /// If a backend is set with [`Self::backend()`], it is the backend's base directory.
+    pub fn realpath_in_root<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<(std::path::PathBuf, std::path::PathBuf), RealpathError> {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        let (in_root, root) =
+            if let Some(root) = self.root.as_ref().filter(|_| self.backend.is_none()) {
+                let root_file = std::fs::File::open(root)?;
+                let in_root = self
+                    .realpath_at(
+                        root_file.as_raw_fd(),
+                        path.as_ref(),
+                        self.flags | RealpathFlags::IN_ROOT,
+                        None,
+                    )?
+                    .0;
+                (
+                    in_root,
+                    self.realpath_at(libc::AT_FDCWD, root, RealpathFlags::empty(), None)?
+                        .0,
+                )
+            } else {
+                let in_root = self
+                    .realpath_at(
+                        self.base_fd(),
+                        path.as_ref(),
+                        self.flags | RealpathFlags::IN_ROOT,
+                        None,
+                    )?
+                    .0;
+                (
+                    in_root,
+                    self.realpath_at(self.base_fd(), ".".as_ref(), RealpathFlags::empty(), None)?
+                        .0,
+                )
+            };
+
+        debug_assert!(in_root.as_os_str().as_bytes().starts_with(b"/"));
+
+        let mut host = root.into_os_string().into_vec();
+        if host == b"/" {
+            host.clear();
+        }
+        if in_root.as_os_str() != "/" || host.is_empty() {
+            host.extend_from_slice(in_root.as_os_str().as_bytes());
+        }
+
+        Ok((in_root, std::ffi::OsString::from_vec(host).into()))
+    }
+
+    /// Get the relative path from `base` to `target`, after canonicalizing both of them.
+    ///
+    /// See [`relpath_raw()`] for more information.
+    pub fn relpath<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+        &self,
+        target: P,
+        base: Q,
+    ) -> Result<std::path::PathBuf, RealpathError> {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        let mut buf = self.realpath(target)?.into_os_string().into_vec();
+        let target_len = buf.len();
+        let base = self.realpath(base)?.into_os_string().into_vec();
+
+        // Each component of `base` (at least 2 bytes including the slash) becomes 3 bytes ("../")
+        buf.resize(target_len + base.len() * 2, 0);
+        let base_start = buf.len() - base.len();
+        buf[base_start..].copy_from_slice(&base);
+
+        let len = make_relative(&mut buf, target_len, base_start)?;
+        buf.truncate(len);
+
+        Ok(std::ffi::OsString::from_vec(buf).into())
+    }
+
+    /// Canonicalize the given path, and open the file it refers to.
+    ///
+    /// This returns the canonicalized path (as [`Self::realpath()`] would) along with a file
+    /// descriptor opened with `O_PATH` on the file that was resolved. Unlike [`Self::realpath()`],
+    /// this walks the path one component at a time with `openat(O_PATH | O_NOFOLLOW)`, so the
+    /// returned file descriptor is guaranteed to refer to the file that was resolved (even if
+    /// the path is modified concurrently). The returned path, however, may be out of date by the
+    /// time this function returns.
+    ///
+    /// `O_PATH` file descriptors can be passed to the `*at()` family of functions (with an empty
+    /// path and `AT_EMPTY_PATH`), or reopened with other flags through `/proc/self/fd/<N>`. With
+    /// [`RealpathFlags::NOFOLLOW_LAST`], the file descriptor refers to the symbolic link itself
+    /// if the last component is one.
+    ///
+    /// This fails with `EINVAL` if [`RealpathFlags::ALLOW_MISSING`],
+    /// [`RealpathFlags::ALLOW_LAST_MISSING`], or [`RealpathFlags::IGNORE_SYMLINKS`] was passed, or
+    /// if a backend was set with [`Self::backend()`]. Other errors record the component that
+    /// failed, as for [`Self::realpath()`] (except that [`RealpathError::prefix()`] is never
+    /// relative to the base directory).
+    ///
+    /// This is only available on Linux and Android.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn realpath_open<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<(std::path::PathBuf, std::os::unix::io::OwnedFd), RealpathError> {
+        use std::os::unix::prelude::*;
+
+        if self.backend.is_some() {
+            return Err(RealpathError::from_errno(libc::EINVAL));
+        }
+
+        let root = self.root.as_ref().map(std::fs::File::open).transpose()?;
+        let (dirfd, flags) = match root.as_ref() {
+            Some(root) => (root.as_raw_fd(), self.flags | RealpathFlags::IN_ROOT),
+            None => (self.base_fd(), self.flags),
+        };
+
+        let mut buf = vec![0; self.max_len];
+        let mut tmp = vec![0; self.max_len + 100];
+
+        let (len, fd) = open::realpath_open_inner(
+            dirfd,
+            path.as_ref().as_os_str().as_bytes(),
+            &mut buf,
+            &mut tmp,
+            flags,
+            self.limits,
+            self.cycles,
+        )?;
+        buf.truncate(len);
+
+        Ok((std::ffi::OsString::from_vec(buf).into(), fd))
+    }
+
+    fn realpath_at(
+        &self,
+        dirfd: libc::c_int,
+        path: &std::path::Path,
+        flags: RealpathFlags,
+        observer: Option<&mut dyn ResolveObserver>,
+    ) -> Result<(std::path::PathBuf, Resolved), RealpathError> {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        let mut buf = vec![0; PATH_MAX.min(self.max_len)];
+
+        if let Some(backend) = self.backend.as_ref() {
+            return self.realpath_with(&**backend, path, buf, flags, observer);
+        }
+
+        // The kernel would report the current path of the base directory, not the known one
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.openat2
+            && observer.is_none()
+            && self.limits.is_empty()
+            && !(path.is_relative() && self.knows_cwd(dirfd))
+        {
+            if let Some(len) =
+                try_realpath_openat2(dirfd, path.as_os_str().as_bytes(), &mut buf, flags)
+            {
+                buf.truncate(len);
+                let res = Resolved {
+                    len,
+                    existing_len: len,
+                };
+                return Ok((std::ffi::OsString::from_vec(buf).into(), res));
+            }
+        }
+
+        self.realpath_with(&self.libc_backend(dirfd, flags), path, buf, flags, observer)
+    }
+
+    /// Get the file descriptor of the directory that relative paths are resolved against.
+    fn base_fd(&self) -> libc::c_int {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        match self.base.as_ref() {
+            Some(base) => base.dir.as_raw_fd(),
+            None => self.dirfd,
+        }
+    }
+
+    /// Check whether [`Self::libc_backend()`] takes the path to `dirfd` from `$PWD` or the base
+    /// directory (instead of asking the OS).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn knows_cwd(&self, dirfd: libc::c_int) -> bool {
+        use std::os::unix::prelude::*;
+
+        (dirfd == libc::AT_FDCWD && self.pwd)
+            || self
+                .base
+                .as_ref()
+                .map_or(false, |base| dirfd == base.dir.as_raw_fd())
+    }
+
+    /// Get the backend that should be used to resolve paths relative to `dirfd` on the OS's
+    /// filesystem.
+    fn libc_backend(
+        &self,
+        dirfd: libc::c_int,
+        flags: RealpathFlags,
+    ) -> backend::KnownCwdBackend<'_> {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        if dirfd == libc::AT_FDCWD && self.pwd {
+            return backend::KnownCwdBackend::from_pwd(flags.contains(RealpathFlags::LOGICAL));
+        }
+
+        // If `dirfd` is the base directory, we already know its path
+        let cwd = self
+            .base
+            .as_ref()
+            .filter(|base| dirfd == base.dir.as_raw_fd())
+            .map(|base| base.path[..].into());
+
+        backend::KnownCwdBackend {
+            inner: LibcBackend::with_dirfd(dirfd),
+            cwd,
+            symlinks: false,
+            logical: false,
+        }
+    }
+
+    /// Call `f` with the backend that should be used to resolve paths relative to `dirfd`
+    /// (wrapped with the cache, if one is set).
+    fn with_backend<R>(
+        &self,
+        dirfd: libc::c_int,
+        flags: RealpathFlags,
+        f: impl FnOnce(&(dyn FsBackend + Sync)) -> R,
+    ) -> R {
+        let libc_backend = self.libc_backend(dirfd, flags);
+        let backend: &(dyn FsBackend + Sync) = match self.backend.as_ref() {
+            Some(backend) => &**backend,
+            None => &libc_backend,
+        };
+
+        match self.cache.as_ref() {
+            Some(cache) => {
+                cache.sync();
+                f(&cache::CachedBackend {
+                    cache,
+                    inner: backend,
+                })
+            }
+            None => f(backend),
+        }
+    }
+
+    fn realpath_with<B: FsBackend + ?Sized>(
+        &self,
+        backend: &B,
+        path: &std::path::Path,
+        buf: Vec<u8>,
+        flags: RealpathFlags,
+        observer: Option<&mut dyn ResolveObserver>,
+    ) -> Result<(std::path::PathBuf, Resolved), RealpathError> {
+        match self.cache.as_ref() {
+            Some(cache) => {
+                cache.sync();
+                self.realpath_retry(
+                    &cache::CachedBackend {
+                        cache,
+                        inner: backend,
+                    },
+                    path,
+                    buf,
+                    flags,
+                    observer,
+                )
+            }
+            None => self.realpath_retry(backend, path, buf, flags, observer),
+        }
+    }
+
+    fn realpath_retry<B: FsBackend + ?Sized>(
+        &self,
+        backend: &B,
+        path: &std::path::Path,
+        mut buf: Vec<u8>,
+        flags: RealpathFlags,
+        mut observer: Option<&mut dyn ResolveObserver>,
+    ) -> Result<(std::path::PathBuf, Resolved), RealpathError> {
+        #[cfg(target_family = "unix")]
+        use std::os::unix::prelude::*;
+        #[cfg(target_os = "wasi")]
+        use std::os::wasi::prelude::*;
+
+        let mut tmp = vec![0; buf.len() + 100];
 
         loop {
-            match realpath_raw_inner(
-                path.as_ref().as_os_str().as_bytes(),
-                &mut buf,
-                &mut tmp,
-                self.flags,
-            ) {
-                Ok(len) => {
-                    buf.truncate(len);
-                    return Ok(std::ffi::OsString::from_vec(buf).into());
+            let res = match observer.as_deref_mut() {
+                Some(observer) => realpath_raw_inner(
+                    backend,
+                    path.as_os_str().as_bytes(),
+                    &mut buf,
+                    &mut tmp,
+                    flags,
+                    self.limits,
+                    self.cycles,
+                    observer,
+                ),
+                None => realpath_raw_inner(
+                    backend,
+                    path.as_os_str().as_bytes(),
+                    &mut buf,
+                    &mut tmp,
+                    flags,
+                    self.limits,
+                    self.cycles,
+                    &mut (),
+                ),
+            };
+
+            match res {
+                Ok(res) => {
+                    buf.truncate(res.len);
+                    return Ok((std::ffi::OsString::from_vec(buf).into(), res));
                 }
 
-                Err(libc::ENAMETOOLONG) if buf.len() < self.max_len => {
+                Err(err) if err.errno() == libc::ENAMETOOLONG && buf.len() < self.max_len => {
                     // Resize until we hit the maximum limit
                     let new_len = buf.len().saturating_mul(2).min(self.max_len);
                     buf.resize(new_len, 0);
                     tmp.resize(new_len + 100, 0);
                 }
-                Err(eno) => return Err(std::io::Error::from_raw_os_error(eno)),
+                Err(err) => return Err(err),
             }
         }
     }
 }
 
+/// Try to resolve `path` with `openat2()`, returning `None` if the normal algorithm must be used.
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+fn try_realpath_openat2(
+    dirfd: libc::c_int,
+    path: &[u8],
+    buf: &mut [u8],
+    flags: RealpathFlags,
+) -> Option<usize> {
+    let mut resolve = 0;
+    let mut rest = flags - RealpathFlags::ALLOW_LAST_MISSING;
+    for &(flag, res) in [
+        (RealpathFlags::NO_SYMLINKS, util::RESOLVE_NO_SYMLINKS),
+        (RealpathFlags::NO_MAGICLINKS, util::RESOLVE_NO_MAGICLINKS),
+        (RealpathFlags::BENEATH, util::RESOLVE_BENEATH),
+    ]
+    .iter()
+    {
+        if rest.contains(flag) {
+            resolve |= res;
+            rest.remove(flag);
+        }
+    }
+
+    // The kernel treats a leading "//" like "/"
+    if !rest.is_empty() || (path.starts_with(b"//") && !path.starts_with(b"///")) {
+        return None;
+    }
+
+    let path = std::ffi::CString::new(path).ok()?;
+    let mut buf = SliceVec::empty(buf);
+    util::realpath_openat2(dirfd, path.as_ptr() as *const u8, resolve, &mut buf).ok()?;
+    Some(buf.len())
+}
+
 #[cfg(feature = "std")]
 impl Default for RealpathBuilder {
     #[inline]
@@ -210,10 +1162,49 @@ impl Default for RealpathBuilder {
 pub fn realpath<P: AsRef<std::path::Path>>(
     path: P,
     flags: RealpathFlags,
-) -> std::io::Result<std::path::PathBuf> {
+) -> Result<std::path::PathBuf, RealpathError> {
     RealpathBuilder::new().flags(flags).realpath(path)
 }
 
+/// Canonicalize the given path, performing all filesystem operations with `backend` (for example,
+/// a [`MemoryFs`]).
+///
+/// This is like [`realpath()`], but relative paths are resolved against the backend's base
+/// directory. See [`FsBackend`] for more information.
+///
+/// `realpath_with_backend(backend, path, flags)` is equivalent to setting `backend` with
+/// [`RealpathBuilder::backend()`] and calling [`RealpathBuilder::realpath()`], except that
+/// `backend` does not have to be wrapped in an `Arc`.
+#[cfg(feature = "std")]
+pub fn realpath_with_backend<P: AsRef<std::path::Path>>(
+    backend: &dyn FsBackend,
+    path: P,
+    flags: RealpathFlags,
+) -> Result<std::path::PathBuf, RealpathError> {
+    let mut builder = RealpathBuilder::new();
+    builder.flags(flags);
+    let buf = vec![0; PATH_MAX.min(builder.max_len)];
+    builder
+        .realpath_with(backend, path.as_ref(), buf, flags, None)
+        .map(|(path, _)| path)
+}
+
+/// Get the relative path from `base` to `target`, after canonicalizing both of them.
+///
+/// This is a wrapper around [`relpath_raw()`] that allocates a buffer; see that function's
+/// documentation for details.
+///
+/// `relpath(target, base, flags)` is equivalent to
+/// `RealpathBuilder::new().flags(flags).relpath(target, base)`.
+#[cfg(feature = "std")]
+pub fn relpath<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+    target: P,
+    base: Q,
+    flags: RealpathFlags,
+) -> Result<std::path::PathBuf, RealpathError> {
+    RealpathBuilder::new().flags(flags).relpath(target, base)
+}
+
 /// A "builder" that allows customizing options to `realpath_raw()`.
 ///
 /// `realpath_raw(path, buf, flags)` is equivalent to
@@ -221,17 +1212,24 @@ pub fn realpath<P: AsRef<std::path::Path>>(
 pub struct RealpathRawBuilder<'a> {
     flags: RealpathFlags,
     tmp: Option<&'a mut [u8]>,
+    dirfd: libc::c_int,
+    limits: util::Limits,
+    backend: Option<&'a dyn FsBackend>,
 }
 
 impl<'a> RealpathRawBuilder<'a> {
     /// Create a new "builder".
     ///
-    /// The returned builder has its `flags` empty, and `temp_buffer` set to `None`.
+    /// The returned builder has its `flags` empty, and `temp_buffer`, `base_dir_fd`,
+    /// `max_symlinks`, `max_components`, `max_link_bytes`, and `backend` set to `None`.
     #[inline]
     pub fn new() -> Self {
         Self {
             flags: RealpathFlags::empty(),
             tmp: None,
+            dirfd: libc::AT_FDCWD,
+            limits: util::Limits::default(),
+            backend: None,
         }
     }
 
@@ -258,15 +1256,144 @@ impl<'a> RealpathRawBuilder<'a> {
         self
     }
 
+    /// Set the directory that relative paths are resolved against.
+    ///
+    /// If `fd` is `None` (default), relative paths are resolved against the current working
+    /// directory. Otherwise, they are resolved against the directory referred to by `fd`; see
+    /// [`realpathat()`] for more information.
+    ///
+    /// This is ignored if a backend is set with [`Self::backend()`].
+    #[inline]
+    pub fn base_dir_fd(&mut self, fd: Option<libc::c_int>) -> &mut Self {
+        self.dirfd = fd.unwrap_or(libc::AT_FDCWD);
+        self
+    }
+
+    /// Set the maximum number of symbolic links that can be followed while resolving a path.
+    ///
+    /// If `max` is `None` (default), the system's `SYMLOOP_MAX` is used (see [`realpath_raw()`]).
+    /// Resolution fails with `ELOOP` if more symbolic links than this are encountered.
+    #[inline]
+    pub fn max_symlinks(&mut self, max: Option<u16>) -> &mut Self {
+        self.limits.symlinks = max;
+        self
+    }
+
+    /// Set the maximum number of components that can be processed while resolving a path.
+    ///
+    /// Every component of the path and of the targets of the symbolic links that are followed
+    /// counts toward this limit (including `..` and `/`, but not `.`). Most components take one
+    /// filesystem operation to resolve, so together with [`Self::max_symlinks()`] and
+    /// [`Self::max_link_bytes()`], this bounds the work done for untrusted paths regardless of
+    /// the system's limits.
+    ///
+    /// If `max` is `None` (default), there is no limit. Resolution fails with `ELOOP` if the
+    /// limit is exceeded.
+    #[inline]
+    pub fn max_components(&mut self, max: Option<usize>) -> &mut Self {
+        self.limits.components = max;
+        self
+    }
+
+    /// Set the maximum total length of the targets of the symbolic links that can be followed
+    /// while resolving a path.
+    ///
+    /// If `max` is `None` (default), there is no limit. Resolution fails with `ELOOP` if the
+    /// limit is exceeded.
+    #[inline]
+    pub fn max_link_bytes(&mut self, max: Option<usize>) -> &mut Self {
+        self.limits.link_bytes = max;
+        self
+    }
+
+    /// Set the filesystem backend used to resolve paths.
+    ///
+    /// If `backend` is `None` (default), the OS's filesystem is used (see [`LibcBackend`]).
+    /// Otherwise, all filesystem operations are performed with the given backend, and relative
+    /// paths are resolved against its base directory. See [`FsBackend`] for more information.
+    #[inline]
+    pub fn backend(&mut self, backend: Option<&'a dyn FsBackend>) -> &mut Self {
+        self.backend = backend;
+        self
+    }
+
     /// Canonicalize the path given by `path` into the buffer given by `buf`.
     ///
     /// `path`, `buf`, and the return value have the same meanings as for [`realpath_raw()`].
     #[inline]
-    pub fn realpath_raw(&mut self, path: &[u8], buf: &mut [u8]) -> Result<usize, i32> {
+    pub fn realpath_raw(&mut self, path: &[u8], buf: &mut [u8]) -> Result<usize, RealpathError> {
+        self.realpath_raw_observed(path, buf, &mut ())
+    }
+
+    /// Canonicalize the path given by `path` into the buffer given by `buf`, also returning which
+    /// part of the result is known to exist.
+    ///
+    /// This is like [`Self::realpath_raw()`], but instead of just the length of the resolved
+    /// path, it returns a [`Resolved`] that also records where the part of the result that was
+    /// verified to exist ends. This is only useful with [`RealpathFlags::ALLOW_MISSING`] or
+    /// [`RealpathFlags::ALLOW_LAST_MISSING`]; otherwise every component of the result exists.
+    pub fn realpath_raw_resolved(
+        &mut self,
+        path: &[u8],
+        buf: &mut [u8],
+    ) -> Result<Resolved, RealpathError> {
+        match self.backend {
+            Some(backend) => self.realpath_raw_with(backend, path, buf, &mut ()),
+            None => {
+                self.realpath_raw_with(&LibcBackend::with_dirfd(self.dirfd), path, buf, &mut ())
+            }
+        }
+    }
+
+    /// Canonicalize the path given by `path` into the buffer given by `buf`, notifying `observer`
+    /// of each step.
+    ///
+    /// See [`ResolveObserver`] for more information.
+    pub fn realpath_raw_observed<O: ResolveObserver + ?Sized>(
+        &mut self,
+        path: &[u8],
+        buf: &mut [u8],
+        observer: &mut O,
+    ) -> Result<usize, RealpathError> {
+        let res = match self.backend {
+            Some(backend) => self.realpath_raw_with(backend, path, buf, observer),
+            None => {
+                self.realpath_raw_with(&LibcBackend::with_dirfd(self.dirfd), path, buf, observer)
+            }
+        };
+        res.map(|res| res.len)
+    }
+
+    fn realpath_raw_with<B: FsBackend + ?Sized, O: ResolveObserver + ?Sized>(
+        &mut self,
+        backend: &B,
+        path: &[u8],
+        buf: &mut [u8],
+        observer: &mut O,
+    ) -> Result<Resolved, RealpathError> {
         if let Some(tmp) = self.tmp.as_mut() {
-            realpath_raw_inner(path, buf, tmp, self.flags)
+            realpath_raw_inner(
+                backend,
+                path,
+                buf,
+                tmp,
+                self.flags,
+                self.limits,
+                false,
+                observer,
+            )
         } else {
-            realpath_raw(path, buf, self.flags)
+            let mut tmp = [0u8; PATH_MAX + 100];
+            realpath_raw_inner(
+                backend,
+                path,
+                buf,
+                &mut tmp,
+                self.flags,
+                self.limits,
+                false,
+                observer,
+            )
         }
     }
 }
@@ -281,15 +1408,17 @@ impl Default for RealpathRawBuilder<'_> {
 /// Canonicalize the given path.
 ///
 /// This function resolves the path specified by `path`, storing the result in `buf`. On success,
-/// the length of the resolved path is returned; on error, an OS error code is returned.
+/// the length of the resolved path is returned; on error, a [`RealpathError`] is returned, which
+/// holds an OS error code (and, with the `std` feature, the component that caused the failure).
 ///
 /// If `flags` is specified as `RealpathFlags::empty()`, this is roughly equivalent to the libc's
 /// `realpath()`. Otherwise, the given `flags` modify aspects of path resolution.
 ///
-/// This function does not allocate any memory. It will only call the following C functions:
+/// This function does not allocate any memory (except to record where an error occurred, with
+/// the `std` feature). It will only call the following C functions:
 /// - `sysconf(_SC_SYMLOOP_MAX)`
-/// - `readlink()`
-/// - `stat()` (only if it needs to be verified that the path is a directory)
+/// - `readlinkat()`
+/// - `fstatat()` (only if it needs to be verified that the path is a directory)
 /// - `getcwd()` (only if the given `path` is relative and does not contain a reference to an
 ///   absolute symbolic link)
 ///
@@ -314,12 +1443,21 @@ impl Default for RealpathRawBuilder<'_> {
 ///    3. An intermediate result created by combining any symbolic link paths exceeded the system
 ///       `PATH_MAX`. (Note that the actual limit is slightly higher than `PATH_MAX` to account for
 ///       storage overhead; this should not be relied upon.)
-/// - `EINVAL`: The given `path` contains a NUL byte (not allowed in \*nix paths).
-/// - `ELOOP`: Too many symbolic links were encounted during resolution.
+/// - `EINVAL`: Either:
+///    1. The given `path` contains a NUL byte (not allowed in \*nix paths), or
+///    2. Both [`RealpathFlags::IN_ROOT`] and [`RealpathFlags::BENEATH`] were passed.
+/// - `ELOOP`: Too many symbolic links were encounted during resolution (or a symbolic link or
+///   magic link was encountered and [`RealpathFlags::NO_SYMLINKS`] or
+///   [`RealpathFlags::NO_MAGICLINKS`] was passed, respectively).
 ///
 ///   This function will use `sysconf()` to check the system's `SYMLOOP_MAX` value to determine
 ///   the limit. If that fails (for example, it always fails on glibc), this function will fall
-///   back on a limit of 40 (which is Linux's limit).
+///   back on a limit of 40 (which is Linux's limit). [`RealpathRawBuilder`] can be used to set a
+///   different limit, along with limits on the number of components processed and the length of
+///   the symbolic links followed (which also fail with `ELOOP` when exceeded).
+///
+///   To find out whether the symbolic links form a loop, see
+///   [`RealpathBuilder::detect_symlink_cycles()`].
 /// - `ENOENT`/`EACCES`/`ENOTDIR`: The given `path` (or a component of it) does not exist, is
 ///   inaccessible, or is not a directory (respectively).
 ///
@@ -328,170 +1466,297 @@ impl Default for RealpathRawBuilder<'_> {
 ///   obtained.
 ///
 ///   (Note that these errors may be ignored depending on the specified `flags`.)
+/// - `EXDEV`: [`RealpathFlags::BENEATH`] was passed, and resolution would have left the current
+///   directory.
+/// - `ENXIO`: A procfs "magic link" was encountered whose target is not a path (see
+///   [`RealpathFlags::NO_MAGICLINKS`]).
 /// - `EIO`: An I/O error occurred while interacting with the filesystem.
-pub fn realpath_raw(path: &[u8], buf: &mut [u8], flags: RealpathFlags) -> Result<usize, i32> {
-    let mut tmp = [0u8; PATH_MAX + 100];
-    realpath_raw_inner(path, buf, &mut tmp, flags)
+pub fn realpath_raw(
+    path: &[u8],
+    buf: &mut [u8],
+    flags: RealpathFlags,
+) -> Result<usize, RealpathError> {
+    realpathat(libc::AT_FDCWD, path, buf, flags)
 }
 
-fn realpath_raw_inner(
+/// Canonicalize the given path, performing all filesystem operations with `backend` (for example,
+/// a [`MemoryFs`]).
+///
+/// This is like [`realpath_raw()`], but relative paths are resolved against the backend's base
+/// directory. See [`FsBackend`] for more information.
+///
+/// `realpath_raw_with_backend(backend, path, buf, flags)` is equivalent to
+/// `RealpathRawBuilder::new().backend(Some(backend)).flags(flags).realpath_raw(path, buf)`.
+pub fn realpath_raw_with_backend(
+    backend: &dyn FsBackend,
     path: &[u8],
     buf: &mut [u8],
-    tmp: &mut [u8],
     flags: RealpathFlags,
-) -> Result<usize, i32> {
-    let mut stack = ComponentStack::new(tmp);
-
-    let mut path_it = ComponentIter::new(path)?;
-
-    let mut buf = SliceVec::empty(buf);
+) -> Result<usize, RealpathError> {
+    RealpathRawBuilder::new()
+        .backend(Some(backend))
+        .flags(flags)
+        .realpath_raw(path, buf)
+}
 
-    let mut links = SymlinkCounter::new();
+/// Canonicalize the given path, resolving relative paths against the directory `dirfd`.
+///
+/// This is like [`realpath_raw()`], except that if `path` is relative, it is resolved against
+/// the directory referred to by the file descriptor `dirfd` instead of the current working
+/// directory (analogous to `openat()`). If `dirfd` is `AT_FDCWD`, this is equivalent to
+/// [`realpath_raw()`]. If `path` is absolute, `dirfd` is ignored.
+///
+/// The returned path will ALWAYS be absolute. If it is necessary to determine the path to
+/// `dirfd` in order to construct it, this function will use the following methods:
+///
+/// - Linux/Android: `readlink()` on `/proc/self/fd/<dirfd>` (so `/proc` must be mounted)
+/// - macOS/iOS/NetBSD/DragonFly BSD: `fcntl(F_GETPATH)`
+///
+/// The path obtained this way is checked against `dirfd` with `fstat()` and `stat()`. On other
+/// OSes, this step fails with `ENOTSUP`.
+///
+/// Example usage:
+///
+/// ```
+/// # use realpath_ext::{RealpathFlags, realpathat};
+/// let mut buf = [0; libc::PATH_MAX as usize];
+/// let n = realpathat(libc::AT_FDCWD, b"/", &mut buf, RealpathFlags::empty()).unwrap();
+/// assert_eq!(&buf[..n], b"/");
+/// ```
+///
+/// # Errors
+///
+/// This function may fail with any of the errors documented for [`realpath_raw()`]. In addition,
+/// it may fail with:
+///
+/// - `EBADF`: `dirfd` is not a valid file descriptor.
+/// - `ENOTDIR`: `dirfd` does not refer to a directory.
+/// - `ENOENT`: The directory referred to by `dirfd` has been removed, or the path obtained for it
+///   no longer refers to it.
+/// - `ENOTSUP`: The path to `dirfd` had to be determined, and that is not supported on this OS.
+///
+/// (With [`RealpathFlags::BENEATH`], `EXDEV` indicates that resolution would have left `dirfd`.)
+pub fn realpathat(
+    dirfd: libc::c_int,
+    path: &[u8],
+    buf: &mut [u8],
+    flags: RealpathFlags,
+) -> Result<usize, RealpathError> {
+    let mut tmp = [0u8; PATH_MAX + 100];
+    realpath_raw_inner(
+        &LibcBackend::with_dirfd(dirfd),
+        path,
+        buf,
+        &mut tmp,
+        flags,
+        util::Limits::default(),
+        false,
+        &mut (),
+    )
+    .map(|res| res.len)
+}
 
-    while let Some(component) = stack.next().or_else(|| path_it.next()) {
-        debug_assert_ne!(buf.as_ref(), b".");
+/// Get the relative path from `base` to `target`, after canonicalizing both of them.
+///
+/// Both `target` and `base` are canonicalized as if with [`realpath_raw()`] (with the given
+/// `flags`), and then the shortest path that leads from `base` to `target` is stored in `buf`
+/// (for example, `../c` for a `target` of `/a/c` and a `base` of `/a/b`). This is similar to
+/// Python's `os.path.relpath()`, except that symbolic links are resolved first, so the result
+/// always refers to `target` when interpreted relative to `base`. If `target` and `base` refer
+/// to the same directory, the result is `.`.
+///
+/// Example usage:
+///
+/// ```
+/// # use realpath_ext::{relpath_raw, RealpathFlags};
+/// let mut buf = [0; libc::PATH_MAX as usize];
+/// let n = relpath_raw(b"/", b"/etc/.//", &mut buf, RealpathFlags::empty()).unwrap();
+/// assert_eq!(&buf[..n], b"..");
+/// ```
+///
+/// # Errors
+///
+/// This function may fail with any of the errors documented for [`realpath_raw()`]. In addition,
+/// it may fail with:
+///
+/// - `EXDEV`: One of the canonicalized paths begins with exactly two slashes and the other does
+///   not. (POSIX allows such paths to be treated specially, so there may not be a relative path
+///   between them.)
+/// - `ENAMETOOLONG`: `buf` is not long enough to store both canonicalized paths at once, or the
+///   relative path.
+pub fn relpath_raw(
+    target: &[u8],
+    base: &[u8],
+    buf: &mut [u8],
+    flags: RealpathFlags,
+) -> Result<usize, RealpathError> {
+    let mut tmp = [0u8; PATH_MAX + 100];
+    let mut resolve = |path: &[u8], buf: &mut [u8]| {
+        realpath_raw_inner(
+            &LibcBackend::new(),
+            path,
+            buf,
+            &mut tmp,
+            flags,
+            util::Limits::default(),
+            false,
+            &mut (),
+        )
+        .map(|res| res.len)
+    };
+
+    // Keep the canonicalized `base` at the end of `buf` while `target` is resolved
+    let base_len = resolve(base, buf)?;
+    let base_start = buf.len() - base_len;
+    buf.copy_within(..base_len, base_start);
+
+    let target_len = resolve(target, &mut buf[..base_start])?;
+    Ok(make_relative(buf, target_len, base_start)?)
+}
 
-        if component == b"/" || component == b"//" {
-            buf.replace(component)?;
-        } else if component == b".." {
-            buf.make_parent_path()?;
+/// Replace the canonicalized path in the first `len` bytes of `buf` with the relative path from
+/// the canonicalized path stored at the end of `buf` (starting at `base_start`, which must be at
+/// least `len`), returning the new length.
+///
+/// The relative path may overwrite `base`.
+fn make_relative(buf: &mut [u8], len: usize, base_start: usize) -> Result<usize, i32> {
+    fn root_len(path: &[u8]) -> usize {
+        if path.starts_with(b"//") {
+            2
         } else {
-            let oldlen = buf.len();
-
-            if !matches!(buf.as_ref(), b"/" | b"//" | b"") {
-                buf.push(b'/')?;
-            }
-            buf.extend_from_slice(component)?;
-            buf.push(b'\0')?;
-
-            let res = if flags.contains(RealpathFlags::IGNORE_SYMLINKS) {
-                // If IGNORE_SYMLINKS was passed, call readlink() to make sure it exists, but then
-                // act like it isn't a symlink if it is
-                Err(unsafe { util::readlink_empty(buf.as_ptr()) }
-                    .err()
-                    .unwrap_or(libc::EINVAL))
-            } else {
-                unsafe { stack.push_readlink(buf.as_ptr()) }
-            };
-
-            match res {
-                Ok(()) => {
-                    links.advance()?;
-                    debug_assert!(buf.len() > oldlen);
-                    buf.truncate(oldlen);
-                }
-
-                // Not a symlink; just remove the trailing NUL
-                Err(libc::EINVAL) => {
-                    buf.pop();
-                }
-
-                // In these conditions, components of the path are allowed to not exist/not be
-                // accessible/not be a directory
-                Err(libc::ENOENT) | Err(libc::EACCES) | Err(libc::ENOTDIR)
-                    if flags.contains(RealpathFlags::ALLOW_MISSING) =>
-                {
-                    buf.pop();
-                }
+            1
+        }
+    }
 
-                Err(libc::ENOENT)
-                    if flags.contains(RealpathFlags::ALLOW_LAST_MISSING)
-                        && stack.is_empty()
-                        && path_it.is_empty() =>
-                {
-                    buf.pop();
-                }
+    debug_assert!(len <= base_start);
+    let target = &buf[..len];
+    let base = &buf[base_start..];
 
-                Err(eno) => return Err(eno),
-            }
-        }
+    debug_assert!(target.starts_with(b"/") && base.starts_with(b"/"));
+    let root = root_len(target);
+    if root != root_len(base) {
+        return Err(libc::EXDEV);
     }
 
-    /// If required, check that `buf` refers to a directory.
-    fn maybe_check_isdir(path: &[u8], buf: &mut SliceVec, flags: RealpathFlags) -> Result<(), i32> {
-        if (path.ends_with(b"/") || path.ends_with(b"/."))
-            && !flags.contains(RealpathFlags::ALLOW_MISSING)
-        {
-            buf.push(b'\0')?;
-            match unsafe { util::check_isdir(buf.as_ptr()) } {
-                Ok(()) => (),
-                Err(libc::ENOENT) if flags.contains(RealpathFlags::ALLOW_LAST_MISSING) => (),
-                Err(eno) => return Err(eno),
+    let mut target_it = target[root..]
+        .split(|&ch| ch == b'/')
+        .filter(|c| !c.is_empty());
+    let mut base_it = base[root..]
+        .split(|&ch| ch == b'/')
+        .filter(|c| !c.is_empty());
+
+    // Find the first component where they differ
+    let mut common = root;
+    let mut parents = 0;
+    loop {
+        match (target_it.next(), base_it.next()) {
+            (Some(t), Some(b)) if t == b => common += t.len() + 1,
+            (_, Some(_)) => {
+                parents = 1 + base_it.count();
+                break;
             }
-            buf.pop();
+            (_, None) => break,
         }
-
-        Ok(())
     }
 
-    let mut tmp = SliceVec::empty(stack.clear());
-
-    if buf.as_ref() == b"" {
-        util::getcwd(&mut buf)?;
-        // We know `buf` refers to a directory
-    } else if buf.as_ref() == b".." {
-        util::getcwd(&mut buf)?;
-        buf.make_parent_path()?;
-        // We know `buf` refers to a directory
-    } else if buf.starts_with(b"../") {
-        let mut n = count_leading_dotdot(&buf);
-        if &buf[(n * 3)..] == b".." {
-            buf.clear();
-            n += 1;
-            // We know `buf` refers to a directory
-        } else {
-            maybe_check_isdir(path, &mut buf, flags)?;
-            buf.remove_range(0..(n * 3 - 1));
-        }
+    let mut buf = SliceVec::empty(buf);
+    buf.set_len(len);
 
-        util::getcwd(&mut tmp)?;
+    // Keep the rest of the target, and add a ".." for every remaining component of the base
+    buf.remove_range(..common.min(buf.len()));
+    for _ in 0..parents {
+        buf.insert_from_slice(0, if buf.is_empty() { b".." } else { b"../" })?;
+    }
+    if buf.is_empty() {
+        buf.push(b'.')?;
+    }
 
-        for _ in 0..n {
-            tmp.make_parent_path()?;
-        }
+    Ok(buf.len())
+}
 
-        buf.insert_from_slice(0, &tmp)?;
-    } else if !buf.starts_with(b"/") {
-        debug_assert!(!buf.starts_with(b"./"));
-        debug_assert_ne!(buf.as_ref(), b".");
+#[allow(clippy::too_many_arguments)]
+fn realpath_raw_inner<B: FsBackend + ?Sized, O: ResolveObserver + ?Sized>(
+    backend: &B,
+    path: &[u8],
+    buf: &mut [u8],
+    tmp: &mut [u8],
+    flags: RealpathFlags,
+    limits: util::Limits,
+    detect_loops: bool,
+    observer: &mut O,
+) -> Result<Resolved, RealpathError> {
+    let mut resolver = Resolver::new(path, buf, tmp, flags)?;
+    resolver.set_limits(limits);
+    #[cfg(feature = "std")]
+    if detect_loops {
+        resolver.detect_loops();
+    }
+    #[cfg(not(feature = "std"))]
+    let _ = detect_loops;
+
+    match run_resolver(&mut resolver, backend, observer)? {
+        Some(len) => Ok(Resolved {
+            len,
+            existing_len: resolver.existing_len().unwrap_or(len),
+        }),
+        None => unreachable!(),
+    }
+}
 
-        maybe_check_isdir(path, &mut buf, flags)?;
+/// Perform the requests made by `resolver` with `backend` until it finishes (returning the length
+/// of the result) or pauses (returning `None`).
+fn run_resolver<B: FsBackend + ?Sized, O: ResolveObserver + ?Sized>(
+    resolver: &mut Resolver<'_>,
+    backend: &B,
+    observer: &mut O,
+) -> Result<Option<usize>, RealpathError> {
+    loop {
+        let step = match resolver.step_pausable(observer) {
+            Ok(step) => step,
+            Err(eno) => {
+                let err = RealpathError::from_errno(eno);
+                #[cfg(feature = "std")]
+                let err = match resolver.error_context() {
+                    Some(context) => err.with_context(context),
+                    None => err,
+                };
+                #[cfg(feature = "std")]
+                let err = match resolver.symlink_cycle() {
+                    Some(cycle) => err.with_cycle(cycle),
+                    None => err,
+                };
+                return Err(err);
+            }
+        };
 
-        tmp.clear();
-        util::getcwd(&mut tmp)?;
-        debug_assert!(tmp.len() > 0);
-        tmp.push(b'/')?;
-        buf.insert_from_slice(0, &tmp)?;
-    } else if !matches!(buf.as_ref(), b"/" | b"//") {
-        // We don't have to check "/" or "//", but we do have to check other paths
-        maybe_check_isdir(path, &mut buf, flags)?;
-    }
+        match step {
+            // Read symlinks and the current directory directly into the resolver's buffers
+            Some(ResolveStep::NeedReadlink(_)) => resolver.readlink_with(backend),
+            Some(ResolveStep::NeedCwd) => resolver.cwd_with(backend),
 
-    Ok(buf.len())
-}
+            Some(ResolveStep::NeedIsDir(path)) => {
+                let res = backend.check_isdir(path);
+                resolver.feed_isdir(res);
+            }
+            Some(ResolveStep::NeedDevice(path)) => {
+                let res = backend.device(path);
+                resolver.feed_device(res);
+            }
+            Some(ResolveStep::NeedIsProcfs(path)) => {
+                let res = backend.is_procfs(path);
+                resolver.feed_is_procfs(res);
+            }
 
-fn count_leading_dotdot(mut s: &[u8]) -> usize {
-    let mut n = 0;
-    while s.starts_with(b"../") {
-        n += 1;
-        s = &s[3..];
+            Some(ResolveStep::Done(len)) => return Ok(Some(len)),
+            None => return Ok(None),
+        }
     }
-    n
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_count_leading_dotdot() {
-        assert_eq!(count_leading_dotdot(b""), 0);
-        assert_eq!(count_leading_dotdot(b".."), 0);
-        assert_eq!(count_leading_dotdot(b"../a"), 1);
-        assert_eq!(count_leading_dotdot(b"../../a"), 2);
-        assert_eq!(count_leading_dotdot(b"../a/../b"), 1);
-    }
-
     #[test]
     fn test_normpath_raw() {
         let mut buf = [0; 100];
@@ -515,6 +1780,39 @@ mod tests {
         assert_eq!(normpath_raw(b"\0", &mut buf).unwrap_err(), libc::EINVAL);
     }
 
+    #[test]
+    fn test_make_relative() {
+        fn check(target: &[u8], base: &[u8], expected: Result<&[u8], i32>) {
+            let mut buf = [0; 100];
+            buf[..target.len()].copy_from_slice(target);
+            buf[100 - base.len()..].copy_from_slice(base);
+            let res = make_relative(&mut buf, target.len(), 100 - base.len());
+            assert_eq!(res.map(|n| &buf[..n]), expected);
+        }
+
+        check(b"/", b"/", Ok(b"."));
+        check(b"/a/b", b"/a/b", Ok(b"."));
+        check(b"/a/b", b"/", Ok(b"a/b"));
+        check(b"/", b"/a/b", Ok(b"../.."));
+        check(b"/a/c", b"/a/b", Ok(b"../c"));
+        check(b"/a/b/c", b"/a", Ok(b"b/c"));
+        check(b"/a", b"/a/b/c", Ok(b"../.."));
+        check(b"/ab", b"/a", Ok(b"../ab"));
+        check(b"/a", b"/ab/c", Ok(b"../../a"));
+        check(b"//a", b"//", Ok(b"a"));
+        check(b"//a/b", b"//a/c", Ok(b"../b"));
+
+        check(b"//a", b"/a", Err(libc::EXDEV));
+        check(b"/a", b"//", Err(libc::EXDEV));
+
+        // The result may overwrite the base, but it must still fit
+        let mut buf = *b"/a/b/c/d";
+        assert_eq!(make_relative(&mut buf, 2, 2), Err(libc::ENAMETOOLONG));
+        let mut buf = *b"/a/b/c/d\0\0";
+        assert_eq!(make_relative(&mut buf, 2, 2), Ok(10));
+        assert_eq!(&buf, b"../../../a");
+    }
+
     #[cfg(feature = "std")]
     #[test]
     fn test_normpath() {
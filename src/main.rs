@@ -6,7 +6,10 @@ use std::os::unix::prelude::*;
 #[cfg(target_os = "wasi")]
 use std::os::wasi::prelude::*;
 
-use realpath_ext::{realpath, RealpathFlags};
+use realpath_ext::{RealpathBuilder, RealpathError, RealpathFlags};
+
+/// How many arguments to resolve (and print) at a time.
+const CHUNK_SIZE: usize = 64;
 
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 enum MissingBehavior {
@@ -106,36 +109,41 @@ fn main() {
     if no_symlinks {
         flags |= RealpathFlags::IGNORE_SYMLINKS;
     }
+    if logical {
+        flags |= RealpathFlags::LOGICAL;
+    }
 
-    let mut error = false;
+    let mut builder = RealpathBuilder::new();
+    builder.flags(flags).use_pwd(logical);
 
-    for path in files.into_iter() {
-        match (|mut path| {
-            if logical {
-                path = realpath(path, flags | RealpathFlags::IGNORE_SYMLINKS)?;
-            }
+    let mut error = false;
 
-            path = realpath(path, flags)?;
+    // Resolve in bounded chunks so that output starts appearing before every argument has been
+    // resolved (and so that a huge argument list doesn't need to be buffered all at once).
+    for chunk in files.chunks(CHUNK_SIZE) {
+        let results = builder.realpath_many(chunk);
 
-            Ok(path)
-        })(path.into())
-        {
-            Ok(path) => {
-                let stdout = std::io::stdout();
-                let mut stdout = stdout.lock();
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
 
-                stdout.write_all(path.as_os_str().as_bytes()).unwrap();
-                stdout.write_all(if zero { b"\0" } else { b"\n" }).unwrap();
-            }
+        for (path, res) in chunk.iter().zip(results) {
+            match res {
+                Ok(path) => {
+                    stdout.write_all(path.as_os_str().as_bytes()).unwrap();
+                    stdout.write_all(if zero { b"\0" } else { b"\n" }).unwrap();
+                }
 
-            Err(e) => {
-                let _: std::io::Error = e;
-                if !quiet {
-                    eprintln!("realpath-ext: {:?}: {}", path, e);
+                Err(e) => {
+                    let _: RealpathError = e;
+                    if !quiet {
+                        eprintln!("realpath-ext: {:?}: {}", path, e);
+                    }
+                    error = true;
                 }
-                error = true;
             }
         }
+
+        stdout.flush().unwrap();
     }
 
     if error {
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::io;
+#[cfg(target_family = "unix")]
+use std::os::unix::prelude::*;
+#[cfg(target_os = "wasi")]
+use std::os::wasi::prelude::*;
+use std::path::Path;
+
+use crate::FsBackend;
+
+const ROOT: usize = 0;
+
+// Linux's limit, as in `SymlinkCounter`
+const MAX_SYMLINKS: u32 = 40;
+
+#[derive(Clone, Debug)]
+enum NodeKind {
+    Dir(BTreeMap<Vec<u8>, usize>),
+    File,
+    Symlink(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    kind: NodeKind,
+    mode: u32,
+    parent: usize,
+    name: Vec<u8>,
+    error: Option<i32>,
+}
+
+/// An in-memory filesystem, for deterministic tests of path resolution.
+///
+/// This implements [`FsBackend`], so it can be passed to [`realpath_with_backend()`],
+/// [`realpath_raw_with_backend()`], [`RealpathBuilder::backend()`], or
+/// [`RealpathRawBuilder::backend()`] to resolve paths without touching the real filesystem. It
+/// holds directories, regular files, and symbolic links, each with a set of permission bits, and
+/// has its own current working directory (initially `/`), which relative paths are resolved
+/// against.
+///
+/// Paths are looked up the way the kernel would (following symbolic links, and failing with
+/// `ENOENT`, `ENOTDIR`, `ELOOP`, etc. as appropriate). Permissions are checked as if the caller
+/// owns every file, so only the owner bits matter: looking up a path fails with `EACCES` if it
+/// passes through a directory without the owner's search (`0o100`) bit set. Errors can also be
+/// injected at specific files with [`Self::inject_error()`].
+///
+/// The methods used to populate the filesystem work like their libc counterparts (for example,
+/// [`Self::mkdir()`] fails with `EEXIST` if the path already exists), except that they ignore
+/// permissions and injected errors. Relative paths passed to them are resolved against the
+/// current working directory.
+///
+/// Example usage:
+///
+/// ```
+/// # use realpath_ext::{realpath_with_backend, MemoryFs, RealpathFlags};
+/// let mut fs = MemoryFs::new();
+/// fs.mkdir("/data", 0o755).unwrap();
+/// fs.mkdir("/data/me", 0o755).unwrap();
+/// fs.symlink("/data/me", "/home").unwrap();
+/// fs.chdir("/home").unwrap();
+///
+/// let path = realpath_with_backend(&fs, "../me", RealpathFlags::empty()).unwrap();
+/// assert_eq!(path.as_os_str(), "/data/me");
+/// ```
+///
+/// Or, to resolve the path into a buffer:
+///
+/// ```
+/// # use realpath_ext::{realpath_raw_with_backend, MemoryFs, RealpathFlags};
+/// let mut fs = MemoryFs::new();
+/// fs.mkdir("/data", 0o755).unwrap();
+/// fs.mkdir("/data/me", 0o755).unwrap();
+/// fs.symlink("/data/me", "/home").unwrap();
+/// fs.chdir("/home").unwrap();
+///
+/// let mut buf = [0; libc::PATH_MAX as usize];
+/// let n = realpath_raw_with_backend(&fs, b"../me", &mut buf, RealpathFlags::empty()).unwrap();
+/// assert_eq!(&buf[..n], b"/data/me");
+/// ```
+///
+/// [`RealpathBuilder::backend()`]: crate::RealpathBuilder::backend
+/// [`RealpathRawBuilder::backend()`]: crate::RealpathRawBuilder::backend
+/// [`realpath_with_backend()`]: crate::realpath_with_backend
+/// [`realpath_raw_with_backend()`]: crate::realpath_raw_with_backend
+#[derive(Clone, Debug)]
+pub struct MemoryFs {
+    nodes: Vec<Node>,
+    cwd: usize,
+}
+
+impl MemoryFs {
+    /// Create a new filesystem containing only the root directory (with mode `0o755`).
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node {
+                kind: NodeKind::Dir(BTreeMap::new()),
+                mode: 0o755,
+                parent: ROOT,
+                name: Vec::new(),
+                error: None,
+            }],
+            cwd: ROOT,
+        }
+    }
+
+    /// Create a directory at `path` with the given permission bits.
+    pub fn mkdir<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> io::Result<()> {
+        self.create(path.as_ref(), NodeKind::Dir(BTreeMap::new()), mode)
+    }
+
+    /// Create a regular file at `path` with the given permission bits.
+    pub fn create_file<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> io::Result<()> {
+        self.create(path.as_ref(), NodeKind::File, mode)
+    }
+
+    /// Create a symbolic link at `path` whose contents are `target`.
+    ///
+    /// As on Linux, `target` cannot be empty.
+    pub fn symlink<T: AsRef<Path>, P: AsRef<Path>>(
+        &mut self,
+        target: T,
+        path: P,
+    ) -> io::Result<()> {
+        let target = target.as_ref().as_os_str().as_bytes();
+        if target.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        self.create(path.as_ref(), NodeKind::Symlink(target.to_vec()), 0o777)
+    }
+
+    /// Change the permission bits of the file at `path` (following symbolic links).
+    pub fn chmod<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> io::Result<()> {
+        let node = self.setup_lookup(path.as_ref(), true)?;
+        self.nodes[node].mode = mode & 0o7777;
+        Ok(())
+    }
+
+    /// Change the current working directory to `path` (following symbolic links).
+    pub fn chdir<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let node = self.setup_lookup(path.as_ref(), true)?;
+        match self.nodes[node].kind {
+            NodeKind::Dir(_) => {
+                self.cwd = node;
+                Ok(())
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    /// Make every operation that looks up the file at `path` (which is not followed if it is a
+    /// symbolic link) fail with `errno`.
+    ///
+    /// This applies whenever the file is reached during a lookup, whether it is the last
+    /// component or an intermediate one, and regardless of the path used to reach it. (Getting
+    /// the path to the current working directory also fails if it is the current directory or one
+    /// of its ancestors.) Pass `None` to remove a previously injected error.
+    pub fn inject_error<P: AsRef<Path>>(&mut self, path: P, errno: Option<i32>) -> io::Result<()> {
+        let node = self.setup_lookup(path.as_ref(), false)?;
+        self.nodes[node].error = errno;
+        Ok(())
+    }
+
+    fn create(&mut self, path: &Path, kind: NodeKind, mode: u32) -> io::Result<()> {
+        let path = path.as_os_str().as_bytes();
+
+        let path = match path.iter().rposition(|&ch| ch != b'/') {
+            Some(i) => &path[..=i],
+            None => return Err(io::Error::from_raw_os_error(libc::EEXIST)),
+        };
+        let (parent, name) = match path.iter().rposition(|&ch| ch == b'/') {
+            Some(i) => (&path[..=i], &path[i + 1..]),
+            None => (&b"."[..], path),
+        };
+
+        let parent = self.setup_lookup(Path::new(std::ffi::OsStr::from_bytes(parent)), true)?;
+        if name == b"." || name == b".." {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let id = self.nodes.len();
+        match self.nodes[parent].kind {
+            NodeKind::Dir(ref mut entries) => {
+                if entries.contains_key(name) {
+                    return Err(io::Error::from_raw_os_error(libc::EEXIST));
+                }
+                entries.insert(name.to_vec(), id);
+            }
+            _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+
+        self.nodes.push(Node {
+            kind,
+            mode: mode & 0o7777,
+            parent,
+            name: name.to_vec(),
+            error: None,
+        });
+
+        Ok(())
+    }
+
+    fn setup_lookup(&self, path: &Path, follow: bool) -> io::Result<usize> {
+        let mut links = 0;
+        self.walk(
+            self.cwd,
+            path.as_os_str().as_bytes(),
+            follow,
+            false,
+            &mut links,
+        )
+        .map_err(io::Error::from_raw_os_error)
+    }
+
+    fn lookup(&self, path: &CStr, follow: bool) -> Result<usize, i32> {
+        let mut links = 0;
+        self.walk(self.cwd, path.to_bytes(), follow, true, &mut links)
+    }
+
+    /// Look up `path` relative to the directory `start`.
+    ///
+    /// If `check` is `true`, permissions and injected errors are checked.
+    fn walk(
+        &self,
+        start: usize,
+        path: &[u8],
+        follow: bool,
+        check: bool,
+        links: &mut u32,
+    ) -> Result<usize, i32> {
+        if path.is_empty() {
+            return Err(libc::ENOENT);
+        }
+
+        let visit = |node: usize| match self.nodes[node].error {
+            Some(eno) if check => Err(eno),
+            _ => Ok(node),
+        };
+
+        let mut cur = visit(if path.starts_with(b"/") { ROOT } else { start })?;
+
+        // A trailing slash means that the last component must be followed (and be a directory)
+        let trailing_slash = path.ends_with(b"/");
+        let mut components = path.split(|&ch| ch == b'/').filter(|c| !c.is_empty());
+        let mut next = components.next();
+
+        while let Some(component) = next {
+            next = components.next();
+
+            let entries = match self.nodes[cur].kind {
+                NodeKind::Dir(ref entries) => entries,
+                _ => return Err(libc::ENOTDIR),
+            };
+            if check && self.nodes[cur].mode & 0o100 == 0 {
+                return Err(libc::EACCES);
+            }
+
+            let dir = cur;
+            cur = match component {
+                b"." => cur,
+                b".." => self.nodes[cur].parent,
+                _ => *entries.get(component).ok_or(libc::ENOENT)?,
+            };
+            cur = visit(cur)?;
+
+            if let NodeKind::Symlink(ref target) = self.nodes[cur].kind {
+                if next.is_some() || follow || trailing_slash {
+                    *links += 1;
+                    if *links > MAX_SYMLINKS {
+                        return Err(libc::ELOOP);
+                    }
+                    cur = self.walk(dir, target, true, check, links)?;
+                }
+            }
+        }
+
+        if trailing_slash && !matches!(self.nodes[cur].kind, NodeKind::Dir(_)) {
+            return Err(libc::ENOTDIR);
+        }
+
+        Ok(cur)
+    }
+}
+
+impl Default for MemoryFs {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FsBackend for MemoryFs {
+    fn readlink(&self, path: &CStr, buf: &mut [u8]) -> Result<usize, i32> {
+        match self.nodes[self.lookup(path, false)?].kind {
+            NodeKind::Symlink(ref target) => {
+                let n = target.len().min(buf.len());
+                buf[..n].copy_from_slice(&target[..n]);
+                Ok(n)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn check_isdir(&self, path: &CStr) -> Result<(), i32> {
+        match self.nodes[self.lookup(path, true)?].kind {
+            NodeKind::Dir(_) => Ok(()),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        let mut names = Vec::new();
+        let mut cur = self.cwd;
+        loop {
+            if let Some(eno) = self.nodes[cur].error {
+                return Err(eno);
+            } else if cur == ROOT {
+                break;
+            }
+            names.push(&self.nodes[cur].name);
+            cur = self.nodes[cur].parent;
+        }
+
+        if names.is_empty() {
+            *buf.first_mut().ok_or(libc::ENAMETOOLONG)? = b'/';
+            return Ok(1);
+        }
+
+        let mut len = 0;
+        for name in names.iter().rev() {
+            let end = len + 1 + name.len();
+            let dest = buf.get_mut(len..end).ok_or(libc::ENAMETOOLONG)?;
+            dest[0] = b'/';
+            dest[1..].copy_from_slice(name);
+            len = end;
+        }
+        Ok(len)
+    }
+
+    fn device(&self, path: &CStr) -> Result<u64, i32> {
+        // Everything is on the same "device"
+        self.lookup(path, false).map(|_| 0)
+    }
+
+    fn file_id(&self, path: &CStr) -> Result<(u64, u64), i32> {
+        // Nodes are never removed, so their indices can serve as inode numbers
+        self.lookup(path, false).map(|node| (0, node as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cstr(s: &str) -> std::ffi::CString {
+        std::ffi::CString::new(s).unwrap()
+    }
+
+    fn sample() -> MemoryFs {
+        let mut fs = MemoryFs::new();
+        fs.mkdir("/a", 0o755).unwrap();
+        fs.mkdir("/a/b", 0o755).unwrap();
+        fs.create_file("/a/b/file", 0o644).unwrap();
+        fs.symlink("b", "/a/link").unwrap();
+        fs.symlink("/a/link/file", "/abs").unwrap();
+        fs.symlink("loop", "/loop").unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_setup_errors() {
+        let mut fs = sample();
+
+        for path in ["/", "/a", "/a/b/file", "/a/link", "/a/b/.."].iter() {
+            assert_eq!(
+                fs.mkdir(path, 0o755).unwrap_err().raw_os_error(),
+                Some(libc::EEXIST),
+                "{:?}",
+                path
+            );
+        }
+        assert_eq!(
+            fs.mkdir("/a/b/file/x", 0o755).unwrap_err().raw_os_error(),
+            Some(libc::ENOTDIR)
+        );
+        assert_eq!(
+            fs.mkdir("/x/y", 0o755).unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            fs.symlink("", "/x").unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            fs.chdir("/abs").unwrap_err().raw_os_error(),
+            Some(libc::ENOTDIR)
+        );
+
+        // Creation through a symlinked directory, and relative to the cwd
+        fs.mkdir("/a/link/c/", 0o755).unwrap();
+        fs.chdir("/a/link/c").unwrap();
+        fs.create_file("../f", 0o600).unwrap();
+        fs.check_isdir(&cstr("/a/b/c")).unwrap();
+        assert_eq!(fs.check_isdir(&cstr("/a/b/f")), Err(libc::ENOTDIR));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut fs = sample();
+        let mut buf = [0; 100];
+
+        assert_eq!(fs.readlink(&cstr("/a/link"), &mut buf), Ok(1));
+        assert_eq!(&buf[..1], b"b");
+        assert_eq!(fs.readlink(&cstr("/abs"), &mut buf[..3]), Ok(3));
+        assert_eq!(&buf[..3], b"/a/");
+        assert_eq!(fs.readlink(&cstr("/a/link/"), &mut buf), Err(libc::EINVAL));
+        assert_eq!(fs.readlink(&cstr("/a/b"), &mut buf), Err(libc::EINVAL));
+        assert_eq!(fs.readlink(&cstr("/a/x"), &mut buf), Err(libc::ENOENT));
+        assert_eq!(fs.readlink(&cstr(""), &mut buf), Err(libc::ENOENT));
+
+        fs.check_isdir(&cstr("/")).unwrap();
+        fs.check_isdir(&cstr("/a/link/../b/.")).unwrap();
+        assert_eq!(fs.check_isdir(&cstr("/abs")), Err(libc::ENOTDIR));
+        assert_eq!(fs.check_isdir(&cstr("/abs/")), Err(libc::ENOTDIR));
+        assert_eq!(fs.check_isdir(&cstr("/abs/x")), Err(libc::ENOTDIR));
+        assert_eq!(fs.check_isdir(&cstr("/loop")), Err(libc::ELOOP));
+        assert_eq!(fs.device(&cstr("/loop")), Ok(0));
+
+        fs.chdir("a/link").unwrap();
+        fs.check_isdir(&cstr("../b")).unwrap();
+        assert_eq!(fs.readlink(&cstr("../link"), &mut buf), Ok(1));
+        let n = fs.getcwd(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"/a/b");
+        assert_eq!(fs.getcwd(&mut buf[..3]), Err(libc::ENAMETOOLONG));
+
+        fs.chdir("/").unwrap();
+        let n = fs.getcwd(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"/");
+        assert_eq!(fs.getcwd(&mut []), Err(libc::ENAMETOOLONG));
+    }
+
+    #[test]
+    fn test_permissions_errors() {
+        let mut fs = sample();
+        let mut buf = [0; 100];
+
+        fs.chmod("/a/link", 0o600).unwrap();
+        assert_eq!(fs.check_isdir(&cstr("/a/b/")), Ok(()));
+        assert_eq!(fs.check_isdir(&cstr("/a/b/.")), Err(libc::EACCES));
+        assert_eq!(fs.readlink(&cstr("/abs"), &mut buf), Ok(12));
+        assert_eq!(fs.check_isdir(&cstr("/abs")), Err(libc::EACCES));
+        fs.chmod("/a/b", 0o755).unwrap();
+
+        fs.inject_error("/a/link", Some(libc::EIO)).unwrap();
+        assert_eq!(fs.readlink(&cstr("/a/link"), &mut buf), Err(libc::EIO));
+        assert_eq!(fs.check_isdir(&cstr("/abs")), Err(libc::EIO));
+        fs.check_isdir(&cstr("/a/b")).unwrap();
+        fs.inject_error("/a/link", None).unwrap();
+        assert_eq!(fs.readlink(&cstr("/a/link"), &mut buf), Ok(1));
+
+        fs.inject_error("/a/b", Some(libc::EIO)).unwrap();
+        assert_eq!(fs.check_isdir(&cstr("/a/link/")), Err(libc::EIO));
+        assert_eq!(fs.readlink(&cstr("/a/link"), &mut buf), Ok(1));
+        fs.chdir("/a/b").unwrap();
+        assert_eq!(fs.getcwd(&mut buf), Err(libc::EIO));
+    }
+}
@@ -0,0 +1,275 @@
+use std::os::unix::prelude::*;
+
+use crate::resolver::{ComponentKind, Current, LoopDetector};
+use crate::slicevec::SliceVec;
+use crate::util::{
+    self, Budget, ComponentIter, ComponentStack, Limits, LogicalSkips, SymlinkCounter,
+};
+use crate::{LibcBackend, RealpathError, RealpathFlags};
+
+unsafe fn openat(dirfd: RawFd, path: *const u8, flags: libc::c_int) -> Result<OwnedFd, i32> {
+    let fd = libc::openat(
+        dirfd,
+        path as *const _,
+        flags | libc::O_PATH | libc::O_CLOEXEC,
+    );
+    if fd < 0 {
+        Err(util::errno_get())
+    } else {
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn fstat(fd: RawFd) -> Result<libc::stat, i32> {
+    let mut buf = core::mem::MaybeUninit::uninit();
+    if unsafe { libc::fstat(fd, buf.as_mut_ptr()) } < 0 {
+        Err(util::errno_get())
+    } else {
+        Ok(unsafe { buf.assume_init() })
+    }
+}
+
+/// Canonicalize the given path, and open the file that it refers to with `O_PATH`.
+///
+/// Unlike `realpath_raw_inner()`, this walks the path one component at a time with
+/// `openat(O_PATH | O_NOFOLLOW)` (reading symbolic links with `readlinkat()` on the returned file
+/// descriptors), so the returned file descriptor refers to the file that was actually resolved.
+///
+/// `ALLOW_MISSING`, `ALLOW_LAST_MISSING`, and `IGNORE_SYMLINKS` are not supported.
+pub fn realpath_open_inner(
+    dirfd: RawFd,
+    path: &[u8],
+    buf: &mut [u8],
+    tmp: &mut [u8],
+    flags: RealpathFlags,
+    limits: Limits,
+    detect_loops: bool,
+) -> Result<(usize, OwnedFd), RealpathError> {
+    let mut buf = SliceVec::empty(buf);
+    let mut current = None;
+    let mut loops = if detect_loops {
+        Some(LoopDetector::default())
+    } else {
+        None
+    };
+
+    match walk(
+        dirfd,
+        path,
+        &mut buf,
+        tmp,
+        flags,
+        limits,
+        &mut current,
+        &mut loops,
+    ) {
+        Ok(fd) => Ok((buf.len(), fd)),
+        Err(eno) => {
+            let err = RealpathError::from_errno(eno);
+            let err = match current {
+                Some(current) => err.with_context(current.error_context(&buf)),
+                None => err,
+            };
+            Err(match loops.as_ref().and_then(|loops| loops.cycle()) {
+                Some(cycle) => err.with_cycle(cycle),
+                None => err,
+            })
+        }
+    }
+}
+
+/// The implementation of [`realpath_open_inner()`], which records the component being processed
+/// in `current` (so the caller can report where it failed) and the symbolic links followed in
+/// `loops` (if set).
+#[allow(clippy::too_many_arguments)]
+fn walk(
+    dirfd: RawFd,
+    path: &[u8],
+    buf: &mut SliceVec,
+    tmp: &mut [u8],
+    flags: RealpathFlags,
+    limits: Limits,
+    current: &mut Option<Current>,
+    loops: &mut Option<LoopDetector>,
+) -> Result<OwnedFd, i32> {
+    let in_root = flags.contains(RealpathFlags::IN_ROOT);
+    let beneath = flags.contains(RealpathFlags::BENEATH);
+
+    if flags.intersects(
+        RealpathFlags::ALLOW_MISSING
+            | RealpathFlags::ALLOW_LAST_MISSING
+            | RealpathFlags::IGNORE_SYMLINKS,
+    ) || (in_root && beneath)
+    {
+        return Err(libc::EINVAL);
+    }
+
+    let mut path_it = ComponentIter::new(path)?;
+
+    let (mut logical, tmp) = if flags.contains(RealpathFlags::LOGICAL) {
+        let (logical, tmp) = LogicalSkips::new(&path_it, tmp)?;
+        (Some(logical), tmp)
+    } else {
+        (None, tmp)
+    };
+
+    let mut stack = ComponentStack::new(tmp);
+
+    let mut links = SymlinkCounter::with_max(limits.symlinks);
+    let mut components = Budget::new(limits.components);
+    let mut link_bytes = Budget::new(limits.link_bytes);
+
+    let base = unsafe { openat(dirfd, b".\0".as_ptr(), libc::O_DIRECTORY) }?;
+
+    let dev = if !flags.contains(RealpathFlags::NO_XDEV) {
+        None
+    } else if path.starts_with(b"/") && !in_root {
+        // Like `RESOLVE_NO_XDEV`, absolute paths start from the root directory
+        let root = unsafe { openat(libc::AT_FDCWD, b"/\0".as_ptr(), 0) }?;
+        Some(fstat(root.as_raw_fd())?.st_dev)
+    } else {
+        Some(fstat(base.as_raw_fd())?.st_dev)
+    };
+    let check_xdev = |st: &libc::stat| match dev {
+        Some(dev) if st.st_dev != dev => Err(libc::EXDEV),
+        _ => Ok(()),
+    };
+
+    if in_root {
+        buf.push(b'/')?;
+    } else if !path.starts_with(b"/") {
+        util::getdirpath(dirfd, buf)?;
+    }
+
+    let mut cur = base
+        .try_clone()
+        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+    // The number of components `cur` is below the base directory (only used with IN_ROOT and
+    // BENEATH)
+    let mut depth = 0usize;
+
+    let nofollow_last = flags.contains(RealpathFlags::NOFOLLOW_LAST)
+        && !(path.ends_with(b"/") || path.ends_with(b"/."));
+    loop {
+        let (component, from_symlink) = match stack.next() {
+            Some(component) => (component, true),
+            None => {
+                let component = match logical.as_mut() {
+                    Some(logical) => logical.next(&mut path_it),
+                    None => path_it.next(),
+                };
+                match component {
+                    Some(component) => (component, false),
+                    None => break,
+                }
+            }
+        };
+
+        *current = Some(Current {
+            kind: match component {
+                b"/" | b"//" => ComponentKind::Root,
+                b".." => ComponentKind::Parent,
+                _ => ComponentKind::Name,
+            },
+            prefix_len: buf.len(),
+            from_symlink,
+        });
+        components.spend(1)?;
+
+        if component == b"/" || component == b"//" {
+            if beneath {
+                return Err(libc::EXDEV);
+            } else if in_root {
+                cur = base
+                    .try_clone()
+                    .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                buf.replace(b"/")?;
+            } else {
+                let root = unsafe { openat(libc::AT_FDCWD, b"/\0".as_ptr(), 0) }?;
+                check_xdev(&fstat(root.as_raw_fd())?)?;
+                cur = root;
+                buf.replace(component)?;
+            }
+            depth = 0;
+        } else if component == b".." {
+            if depth == 0 {
+                if in_root {
+                    continue;
+                } else if beneath {
+                    return Err(libc::EXDEV);
+                }
+            }
+
+            let parent = unsafe { openat(cur.as_raw_fd(), b"..\0".as_ptr(), libc::O_DIRECTORY) }?;
+            check_xdev(&fstat(parent.as_raw_fd())?)?;
+            cur = parent;
+            buf.make_parent_path()?;
+            depth = depth.saturating_sub(1);
+        } else {
+            let oldlen = buf.len();
+
+            if !matches!(buf.as_ref(), b"/" | b"//" | b"") {
+                buf.push(b'/')?;
+            }
+            let start = buf.len();
+            buf.extend_from_slice(component)?;
+            buf.push(b'\0')?;
+
+            let next = unsafe { openat(cur.as_raw_fd(), buf[start..].as_ptr(), libc::O_NOFOLLOW) }?;
+            let st = fstat(next.as_raw_fd())?;
+
+            let is_last = stack.is_empty()
+                && match logical.as_ref() {
+                    Some(logical) => logical.is_empty(),
+                    None => path_it.is_empty(),
+                };
+
+            if st.st_mode & libc::S_IFMT == libc::S_IFLNK && !(nofollow_last && is_last) {
+                if flags.contains(RealpathFlags::NO_SYMLINKS) {
+                    return Err(libc::ELOOP);
+                }
+
+                // readlinkat() with an empty path reads the link that the O_PATH file descriptor
+                // refers to
+                stack.push_readlink(
+                    &LibcBackend::with_dirfd(next.as_raw_fd()),
+                    util::cstr(b"\0")?,
+                )?;
+                util::check_magic_link(stack.top(), flags, || {
+                    util::is_procfs_fd(next.as_raw_fd())
+                })?;
+                if let Some(loops) = loops.as_mut() {
+                    #[allow(clippy::unnecessary_cast)]
+                    let id = (st.st_dev as u64, st.st_ino as u64);
+                    let link = &buf[..buf.len() - 1];
+                    if loops.visit(link, Some(id), stack.remaining(), path_it.as_bytes()) {
+                        return Err(libc::ELOOP);
+                    }
+                }
+                links.advance()?;
+                link_bytes.spend(stack.top().len())?;
+                buf.truncate(oldlen);
+            } else {
+                check_xdev(&st)?;
+                buf.pop();
+                cur = next;
+                depth += 1;
+            }
+        }
+    }
+
+    *current = Some(Current {
+        kind: ComponentKind::Final,
+        prefix_len: 0,
+        from_symlink: false,
+    });
+    if (path.ends_with(b"/") || path.ends_with(b"/."))
+        && fstat(cur.as_raw_fd())?.st_mode & libc::S_IFMT != libc::S_IFDIR
+    {
+        return Err(libc::ENOTDIR);
+    }
+
+    debug_assert!(buf.starts_with(b"/"));
+
+    Ok(cur)
+}
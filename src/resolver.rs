@@ -0,0 +1,1131 @@
+use core::ffi::CStr;
+
+use crate::slicevec::SliceVec;
+use crate::util::{
+    self, Budget, ComponentIter, ComponentStack, Limits, LogicalSkips, SymlinkCounter,
+};
+use crate::{FsBackend, RealpathFlags, ResolveObserver};
+
+/// A request made by a [`Resolver`], or the result of resolution.
+///
+/// All paths are either absolute or relative to the current working directory (or base
+/// directory), as described for [`FsBackend`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveStep<'r> {
+    /// Read the contents of the symbolic link at the given path, and pass them to
+    /// [`Resolver::feed_readlink()`].
+    NeedReadlink(&'r CStr),
+    /// Check whether the given path (following symbolic links) refers to a directory, and pass
+    /// the result to [`Resolver::feed_isdir()`].
+    NeedIsDir(&'r CStr),
+    /// Get the path to the current working directory (or base directory), and pass it to
+    /// [`Resolver::feed_cwd()`].
+    NeedCwd,
+    /// Get the ID of the device that the given path (which is not followed if it is a symbolic
+    /// link) resides on, and pass it to [`Resolver::feed_device()`].
+    ///
+    /// This is only requested with [`RealpathFlags::NO_XDEV`].
+    NeedDevice(&'r CStr),
+    /// Check whether the symbolic link at the given path resides on a procfs filesystem, and pass
+    /// the result to [`Resolver::feed_is_procfs()`].
+    ///
+    /// This is only requested on Linux and Android (see [`RealpathFlags::NO_MAGICLINKS`]).
+    NeedIsProcfs(&'r CStr),
+    /// Resolution is finished, and the canonicalized path is stored in the first `N` bytes of
+    /// the `buf` passed to [`Resolver::new()`].
+    Done(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Request {
+    Readlink,
+    IsDir,
+    Cwd,
+    Device,
+    IsProcfs,
+}
+
+/// What the component that a [`Resolver`] is currently processing is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ComponentKind {
+    /// `/` or `//`
+    Root,
+    /// `..`
+    Parent,
+    /// A normal component (which is appended to `buf`)
+    Name,
+    /// The whole path is being checked (e.g. to see if it refers to a directory)
+    Final,
+}
+
+/// The component that a [`Resolver`] is currently processing (for error reporting).
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) struct Current {
+    pub kind: ComponentKind,
+    /// The length of `buf` before this component was processed
+    pub prefix_len: usize,
+    /// Whether this component came from the target of a symbolic link
+    pub from_symlink: bool,
+}
+
+impl Current {
+    /// Get the context for an error that occurred while processing this component, given the
+    /// contents of `buf` at the time.
+    #[cfg(feature = "std")]
+    pub(crate) fn error_context(self, buf: &[u8]) -> ErrorContext<'_> {
+        let buf = buf.strip_suffix(b"\0").unwrap_or(buf);
+        let prefix = &buf[..self.prefix_len.min(buf.len())];
+
+        let (prefix, component): (&[u8], &[u8]) = match self.kind {
+            ComponentKind::Root => (prefix, b"/"),
+            ComponentKind::Parent => (prefix, b".."),
+            ComponentKind::Name => {
+                let component = &buf[prefix.len()..];
+                let component = match prefix {
+                    b"" | b"/" | b"//" => component,
+                    _ => component.strip_prefix(b"/").unwrap_or(component),
+                };
+                (prefix, component)
+            }
+            ComponentKind::Final => match buf.iter().rposition(|&ch| ch == b'/') {
+                Some(i) => (&buf[..i.max(1)], &buf[i + 1..]),
+                None => (b"", buf),
+            },
+        };
+
+        ErrorContext {
+            prefix,
+            component,
+            from_symlink: self.from_symlink,
+        }
+    }
+}
+
+/// Where resolution failed (see [`Resolver::error_context()`]).
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ErrorContext<'r> {
+    /// The path that had been resolved before the failing component
+    pub prefix: &'r [u8],
+    /// The component that could not be resolved
+    pub component: &'r [u8],
+    /// Whether the component came from the target of a symbolic link
+    pub from_symlink: bool,
+}
+
+/// Records the symbolic links followed by a [`Resolver`] to find loops (see
+/// [`Resolver::detect_loops()`]).
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub(crate) struct LoopDetector {
+    /// The paths to the links that have been followed, in order
+    links: Vec<Vec<u8>>,
+    /// Maps each link and the rest of the path after following it (the components on the stack,
+    /// then the rest of the original path) to its index in `links`
+    seen: std::collections::HashMap<(LinkId, Vec<u8>), usize>,
+    /// The index in `links` where the loop starts (once one has been found)
+    cycle: Option<usize>,
+}
+
+/// How a [`LoopDetector`] identifies a symbolic link.
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum LinkId {
+    /// The device and inode number of the link
+    Inode(u64, u64),
+    /// The path to the link (if the backend can't report inode numbers)
+    Path(Vec<u8>),
+}
+
+#[cfg(feature = "std")]
+impl LoopDetector {
+    /// Record that the link at `link` (with the device and inode number `id`, if known) was
+    /// followed, returning `true` if it was already followed with the same `stack` and `path`
+    /// left to resolve.
+    pub fn visit(
+        &mut self,
+        link: &[u8],
+        id: Option<(u64, u64)>,
+        stack: &[u8],
+        path: &[u8],
+    ) -> bool {
+        use std::collections::hash_map::Entry;
+
+        let id = match id {
+            Some((dev, ino)) => LinkId::Inode(dev, ino),
+            None => LinkId::Path(link.to_vec()),
+        };
+        // Paths cannot contain NUL bytes, so the last one separates the stack from the path
+        let rest = [stack, b"\0", path].concat();
+        self.links.push(link.to_vec());
+        match self.seen.entry((id, rest)) {
+            Entry::Occupied(entry) => {
+                self.cycle = Some(*entry.get());
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(self.links.len() - 1);
+                false
+            }
+        }
+    }
+
+    /// If a loop has been found, get the paths to the links in it, in the order they were
+    /// followed (starting and ending with the same link).
+    pub fn cycle(&self) -> Option<&[Vec<u8>]> {
+        Some(&self.links[self.cycle?..])
+    }
+}
+
+/// The state of a [`Resolver`] that has been paused part of the way through its path (see
+/// [`Resolver::pause_at()`]), not including the contents of its `buf`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Snapshot {
+    links: SymlinkCounter,
+    components: Budget,
+    link_bytes: Budget,
+    dev: Option<u64>,
+    existing_len: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    /// Resolution has not started
+    Start,
+    /// Waiting for the device of the base directory (or the root directory, for an absolute path)
+    BaseDevice,
+    /// Ready to process the next component
+    Next,
+    /// Waiting for the device of the root directory (`double` is true for `//`)
+    Root {
+        double: bool,
+    },
+    /// Waiting for the device of a directory above the base directory
+    Parent,
+    /// Waiting for the contents of the symbolic link that was just appended
+    Readlink {
+        oldlen: usize,
+    },
+    /// Waiting to find out whether the symbolic link that was just appended is a magic link
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    MagicLink {
+        oldlen: usize,
+    },
+    /// Waiting for the device of the component that was just appended
+    Component,
+    /// All components have been processed
+    Finish,
+    /// Waiting to find out whether the path refers to a directory
+    IsDir,
+    /// Waiting for the path to the current directory
+    Cwd,
+    Done(usize),
+    Failed(i32),
+}
+
+/// A "sans-IO" implementation of the path resolution algorithm used by [`realpath_raw()`].
+///
+/// Instead of calling into the filesystem, a `Resolver` asks the caller to perform each operation
+/// it needs: [`Self::step()`] returns a [`ResolveStep`] describing the next request, and the
+/// caller passes the result back with the corresponding `feed_*()` method before calling
+/// [`Self::step()`] again. This allows driving resolution from an async runtime, over a network
+/// connection, and so on, with exactly the same semantics as [`realpath_raw()`] (including the
+/// handling of [`RealpathFlags`], and of paths beginning with `//`).
+///
+/// Example usage (performing each request with [`LibcBackend`]):
+///
+/// ```
+/// # use realpath_ext::{FsBackend, LibcBackend, RealpathFlags, ResolveStep, Resolver};
+/// let backend = LibcBackend::new();
+///
+/// let mut buf = [0; libc::PATH_MAX as usize];
+/// let mut tmp = [0; libc::PATH_MAX as usize + 100];
+/// let mut resolver = Resolver::new(b"/.//", &mut buf, &mut tmp, RealpathFlags::empty()).unwrap();
+///
+/// let len = loop {
+///     match resolver.step().unwrap() {
+///         ResolveStep::NeedReadlink(path) => {
+///             let mut target = [0; libc::PATH_MAX as usize];
+///             let res = backend.readlink(path, &mut target).map(|n| &target[..n]);
+///             resolver.feed_readlink(res);
+///         }
+///         ResolveStep::NeedIsDir(path) => {
+///             let res = backend.check_isdir(path);
+///             resolver.feed_isdir(res);
+///         }
+///         ResolveStep::NeedCwd => {
+///             let mut cwd = [0; libc::PATH_MAX as usize];
+///             let res = backend.getcwd(&mut cwd).map(|n| &cwd[..n]);
+///             resolver.feed_cwd(res);
+///         }
+///         ResolveStep::NeedDevice(path) => {
+///             let res = backend.device(path);
+///             resolver.feed_device(res);
+///         }
+///         ResolveStep::NeedIsProcfs(path) => {
+///             let res = backend.is_procfs(path);
+///             resolver.feed_is_procfs(res);
+///         }
+///         ResolveStep::Done(len) => break len,
+///     }
+/// };
+///
+/// assert_eq!(&buf[..len], b"/");
+/// ```
+///
+/// If [`Self::step()`] fails, all later calls will fail with the same error.
+///
+/// [`realpath_raw()`]: crate::realpath_raw
+/// [`LibcBackend`]: crate::LibcBackend
+#[derive(Debug)]
+pub struct Resolver<'a> {
+    stack: ComponentStack<'a>,
+    path_it: ComponentIter<'a>,
+    /// The components of the path that are skipped (with `LOGICAL`)
+    logical: Option<LogicalSkips<'a>>,
+    path_len: usize,
+    /// Pause when this many bytes of the path have been resolved (see `pause_at()`)
+    pause_at: Option<usize>,
+    buf: SliceVec<'a>,
+    links: SymlinkCounter,
+    /// The number of components that can still be processed
+    components: Budget,
+    /// The number of bytes of symbolic link targets that can still be followed
+    link_bytes: Budget,
+    flags: RealpathFlags,
+    /// Whether the original path ended with `/` or `/.` (so the result must be a directory)
+    dir_required: bool,
+    /// The device that all components must reside on (with NO_XDEV)
+    dev: Option<u64>,
+    /// The length of the path to the current directory (stored at the start of the stack's
+    /// buffer once all components have been processed)
+    cwd_len: usize,
+    /// The component being processed (if any)
+    current: Option<Current>,
+    /// If any components of `buf` were allowed to be missing, the length of the part before them
+    existing_len: Option<usize>,
+    /// Set by `detect_loops()`
+    #[cfg(feature = "std")]
+    loops: Option<LoopDetector>,
+    /// The device and inode number of the symbolic link that was just read (only with
+    /// `detect_loops()`, and if the backend reports them)
+    #[cfg(feature = "std")]
+    link_id: Option<(u64, u64)>,
+    state: State,
+    pending: Option<Request>,
+    response: Option<Result<u64, i32>>,
+}
+
+impl<'a> Resolver<'a> {
+    /// Create a new resolver for the given `path`.
+    ///
+    /// The canonicalized path will be stored in `buf`, and `tmp` is used to store intermediate
+    /// results. These buffers, and the given `flags`, have the same meaning as for
+    /// [`RealpathRawBuilder`](crate::RealpathRawBuilder) (in particular, `tmp` should be somewhat
+    /// larger than `buf`).
+    ///
+    /// This fails with `ENOENT` if `path` is empty, or `EINVAL` if it contains a NUL byte or both
+    /// [`RealpathFlags::IN_ROOT`] and [`RealpathFlags::BENEATH`] were passed.
+    pub fn new(
+        path: &'a [u8],
+        buf: &'a mut [u8],
+        tmp: &'a mut [u8],
+        flags: RealpathFlags,
+    ) -> Result<Self, i32> {
+        let path_it = ComponentIter::new(path)?;
+
+        if flags.contains(RealpathFlags::IN_ROOT | RealpathFlags::BENEATH) {
+            return Err(libc::EINVAL);
+        }
+
+        let (logical, tmp) = if flags.contains(RealpathFlags::LOGICAL) {
+            let (logical, tmp) = LogicalSkips::new(&path_it, tmp)?;
+            (Some(logical), tmp)
+        } else {
+            (None, tmp)
+        };
+
+        // With IN_ROOT, `buf` always holds an absolute path relative to the root. The leading
+        // slash is stripped to get a path that is relative to the base directory.
+        let mut buf = SliceVec::empty(buf);
+        if flags.contains(RealpathFlags::IN_ROOT) {
+            buf.push(b'/')?;
+        }
+
+        Ok(Self {
+            stack: ComponentStack::new(tmp),
+            path_it,
+            logical,
+            path_len: path.len(),
+            pause_at: None,
+            buf,
+            links: SymlinkCounter::new(),
+            components: Budget::new(None),
+            link_bytes: Budget::new(None),
+            flags,
+            dir_required: path.ends_with(b"/") || path.ends_with(b"/."),
+            dev: None,
+            cwd_len: 0,
+            current: None,
+            existing_len: None,
+            #[cfg(feature = "std")]
+            loops: None,
+            #[cfg(feature = "std")]
+            link_id: None,
+            state: State::Start,
+            pending: None,
+            response: None,
+        })
+    }
+
+    /// Advance resolution until the next request (or the end).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous request has not been responded to.
+    #[inline]
+    pub fn step(&mut self) -> Result<ResolveStep<'_>, i32> {
+        self.step_observed(&mut ())
+    }
+
+    /// Advance resolution until the next request (or the end), notifying `observer` of each step
+    /// taken.
+    ///
+    /// See [`ResolveObserver`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous request has not been responded to.
+    pub fn step_observed<O: ResolveObserver + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<ResolveStep<'_>, i32> {
+        // Resolution can only be paused if pause_at() was called
+        Ok(self
+            .step_pausable(observer)?
+            .expect("resolution was paused"))
+    }
+
+    /// Like [`Self::step_observed()`], but returns `None` if resolution reaches the point set
+    /// with [`Self::pause_at()`].
+    pub(crate) fn step_pausable<O: ResolveObserver + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<Option<ResolveStep<'_>>, i32> {
+        if let Some(req) = self.pending {
+            assert!(
+                self.response.is_some(),
+                "no response was given for the previous request ({:?})",
+                req
+            );
+        }
+        self.pending = None;
+
+        let req = match self.advance(observer) {
+            Ok(Some(req)) => req,
+            Ok(None) => match self.state {
+                State::Done(len) => return Ok(Some(ResolveStep::Done(len))),
+                State::Next => return Ok(None),
+                _ => unreachable!(),
+            },
+            Err(eno) => {
+                self.state = State::Failed(eno);
+                return Err(eno);
+            }
+        };
+
+        self.pending = Some(req);
+
+        if req == Request::Cwd {
+            return Ok(Some(ResolveStep::NeedCwd));
+        }
+
+        let path = match self.state {
+            // Like `RESOLVE_NO_XDEV`, absolute paths start from the root directory
+            State::BaseDevice
+                if self.path_it.as_bytes().starts_with(b"/")
+                    && !self.flags.contains(RealpathFlags::IN_ROOT) =>
+            {
+                util::cstr(b"/\0")?
+            }
+            State::BaseDevice => util::cstr(b".\0")?,
+            State::Root { .. } => util::cstr(b"/\0")?,
+            _ => util::cstr(&self.buf[self.skip()..])?,
+        };
+
+        Ok(Some(match req {
+            Request::Readlink => ResolveStep::NeedReadlink(path),
+            Request::IsDir => ResolveStep::NeedIsDir(path),
+            Request::Device => ResolveStep::NeedDevice(path),
+            Request::IsProcfs => ResolveStep::NeedIsProcfs(path),
+            Request::Cwd => unreachable!(),
+        }))
+    }
+
+    /// Limit the work done while resolving the path (see [`Limits`]).
+    ///
+    /// This must be called before [`Self::step()`] is called.
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        debug_assert!(matches!(self.state, State::Start));
+
+        self.links = SymlinkCounter::with_max(limits.symlinks);
+        self.components = Budget::new(limits.components);
+        self.link_bytes = Budget::new(limits.link_bytes);
+    }
+
+    /// Fail with `ELOOP` as soon as a symbolic link is followed for a second time with the same
+    /// path left to resolve after it (which means resolution would loop forever), recording the
+    /// links in the loop (see [`Self::symlink_cycle()`]).
+    ///
+    /// Links are identified by their device and inode numbers (see [`FsBackend::file_id()`]),
+    /// which are only looked up when this is enabled (and only if the resolver is driven by
+    /// [`Self::readlink_with()`]). This also allocates memory for every symbolic link that is
+    /// followed.
+    #[cfg(feature = "std")]
+    pub(crate) fn detect_loops(&mut self) {
+        self.loops = Some(LoopDetector::default());
+    }
+
+    /// If resolution failed because of a loop of symbolic links (with [`Self::detect_loops()`]),
+    /// get the paths to the links in the loop, in the order they were followed (starting and
+    /// ending with the same link).
+    #[cfg(feature = "std")]
+    pub(crate) fn symlink_cycle(&self) -> Option<&[Vec<u8>]> {
+        self.loops.as_ref()?.cycle()
+    }
+
+    /// Make [`Self::step_pausable()`] pause once the first `offset` bytes of the path have been
+    /// fully resolved.
+    ///
+    /// `offset` must be the start of a component (after any slashes); otherwise resolution will
+    /// not pause.
+    #[cfg(feature = "std")]
+    pub(crate) fn pause_at(&mut self, offset: usize) {
+        self.pause_at = Some(offset);
+    }
+
+    /// Get the state of a paused resolver, along with the path that it has resolved so far.
+    ///
+    /// Passing these to [`Self::restore()`] on a resolver for any other path that begins with the
+    /// same `offset` bytes (and has more components after them) resumes resolution from the same
+    /// point.
+    #[cfg(feature = "std")]
+    pub(crate) fn snapshot(&self) -> (Snapshot, &[u8]) {
+        debug_assert!(matches!(self.state, State::Next));
+        debug_assert!(self.stack.is_empty());
+
+        let snapshot = Snapshot {
+            links: self.links,
+            components: self.components,
+            link_bytes: self.link_bytes,
+            dev: self.dev,
+            existing_len: self.existing_len,
+        };
+        (snapshot, &self.buf)
+    }
+
+    /// Skip the first `offset` bytes of the path, restoring the state saved by
+    /// [`Self::snapshot()`].
+    ///
+    /// This must be called before [`Self::step()`] is called.
+    #[cfg(feature = "std")]
+    pub(crate) fn restore(
+        &mut self,
+        offset: usize,
+        snapshot: Snapshot,
+        prefix: &[u8],
+    ) -> Result<(), i32> {
+        debug_assert!(matches!(self.state, State::Start));
+
+        self.path_it = ComponentIter::new(&self.path_it.as_bytes()[offset..])?;
+        if let Some(logical) = self.logical.as_mut() {
+            logical.reset(&self.path_it);
+        }
+        self.buf.replace(prefix)?;
+        self.links = snapshot.links;
+        self.components = snapshot.components;
+        self.link_bytes = snapshot.link_bytes;
+        self.dev = snapshot.dev;
+        self.existing_len = snapshot.existing_len;
+        self.state = State::Next;
+        Ok(())
+    }
+
+    /// Once resolution has finished, get the length of the part of the result that is known to
+    /// exist.
+    ///
+    /// This is the length of the whole result unless [`RealpathFlags::ALLOW_MISSING`] or
+    /// [`RealpathFlags::ALLOW_LAST_MISSING`] allowed some of its components to be missing (or
+    /// inaccessible). In that case, it is the length of the path to the last directory that was
+    /// found to exist (without a trailing slash), and all components after it were assumed.
+    pub fn existing_len(&self) -> Option<usize> {
+        match self.state {
+            State::Done(len) => Some(self.existing_len.unwrap_or(len)),
+            _ => None,
+        }
+    }
+
+    /// If resolution has failed, get the component that caused the failure (if it was caused by a
+    /// specific component).
+    #[cfg(feature = "std")]
+    pub(crate) fn error_context(&self) -> Option<ErrorContext<'_>> {
+        if !matches!(self.state, State::Failed(_)) {
+            return None;
+        }
+        Some(self.current?.error_context(&self.buf))
+    }
+
+    /// Respond to a [`ResolveStep::NeedReadlink`] request with the contents of the symbolic link
+    /// (or an error, which should be `EINVAL` if the path is not a symbolic link).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the last request was not [`ResolveStep::NeedReadlink`].
+    pub fn feed_readlink(&mut self, res: Result<&[u8], i32>) {
+        self.expect(Request::Readlink);
+
+        let res = match res {
+            Ok(target) if !self.probe_symlinks() => self.stack.push(target),
+            Ok(_) => Ok(()),
+            Err(eno) => Err(eno),
+        };
+        self.response = Some(res.map(|()| 0));
+    }
+
+    /// Respond to a [`ResolveStep::NeedIsDir`] request (with `Ok(())` if the path refers to a
+    /// directory, or an error otherwise, which should be `ENOTDIR` if it exists but is not a
+    /// directory).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the last request was not [`ResolveStep::NeedIsDir`].
+    pub fn feed_isdir(&mut self, res: Result<(), i32>) {
+        self.expect(Request::IsDir);
+        self.response = Some(res.map(|()| 0));
+    }
+
+    /// Respond to a [`ResolveStep::NeedCwd`] request with the path to the current directory (or
+    /// an error).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the last request was not [`ResolveStep::NeedCwd`].
+    pub fn feed_cwd(&mut self, res: Result<&[u8], i32>) {
+        self.expect(Request::Cwd);
+
+        let mut cwd = SliceVec::empty(self.stack.clear());
+        let res = res.and_then(|path| {
+            if !path.starts_with(b"/") {
+                return Err(libc::ENOENT);
+            }
+            cwd.replace(path)
+        });
+        self.cwd_len = cwd.len();
+        self.response = Some(res.map(|()| 0));
+    }
+
+    /// Respond to a [`ResolveStep::NeedDevice`] request with the ID of the device (or an error).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the last request was not [`ResolveStep::NeedDevice`].
+    pub fn feed_device(&mut self, res: Result<u64, i32>) {
+        self.expect(Request::Device);
+        self.response = Some(res);
+    }
+
+    /// Respond to a [`ResolveStep::NeedIsProcfs`] request with whether the symbolic link resides
+    /// on a procfs filesystem (or an error).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the last request was not [`ResolveStep::NeedIsProcfs`].
+    pub fn feed_is_procfs(&mut self, res: Result<bool, i32>) {
+        self.expect(Request::IsProcfs);
+        self.response = Some(res.map(u64::from));
+    }
+
+    /// Respond to a [`ResolveStep::NeedReadlink`] request by reading the symbolic link with
+    /// `backend` directly into the stack.
+    pub(crate) fn readlink_with<B: FsBackend + ?Sized>(&mut self, backend: &B) {
+        self.expect(Request::Readlink);
+
+        let probe = self.probe_symlinks();
+        let res = match util::cstr(&self.buf[self.skip()..]) {
+            Ok(path) if probe => util::readlink_empty(backend, path),
+            Ok(path) => {
+                let res = self.stack.push_readlink(backend, path);
+                #[cfg(feature = "std")]
+                if res.is_ok() && self.loops.is_some() {
+                    self.link_id = backend.file_id(path).ok();
+                }
+                res
+            }
+            Err(eno) => Err(eno),
+        };
+        self.response = Some(res.map(|()| 0));
+    }
+
+    /// Respond to a [`ResolveStep::NeedCwd`] request by getting the path to the current
+    /// directory with `backend` directly into the stack's buffer.
+    pub(crate) fn cwd_with<B: FsBackend + ?Sized>(&mut self, backend: &B) {
+        self.expect(Request::Cwd);
+
+        // Leading `..` components were resolved by the backend, unless LOGICAL was passed
+        let physical = !self.flags.contains(RealpathFlags::LOGICAL)
+            && matches!(self.relative_parts(), Some((n, _)) if n > 0);
+
+        let mut cwd = SliceVec::empty(self.stack.clear());
+        let res = util::backend_getcwd(backend, &mut cwd, physical);
+        self.cwd_len = cwd.len();
+        self.response = Some(res.map(|()| 0));
+    }
+
+    #[inline]
+    fn expect(&self, req: Request) {
+        assert_eq!(
+            self.pending,
+            Some(req),
+            "response does not match the last request"
+        );
+        debug_assert!(self.response.is_none());
+    }
+
+    #[inline]
+    fn take_response(&mut self) -> Result<u64, i32> {
+        self.response
+            .take()
+            .expect("no response was given for the previous request")
+    }
+
+    /// The number of bytes to strip from the start of `buf` to get a path relative to the base
+    /// directory.
+    #[inline]
+    fn skip(&self) -> usize {
+        self.flags.contains(RealpathFlags::IN_ROOT) as usize
+    }
+
+    /// Whether the contents of the symbolic link being looked up are not needed (only whether the
+    /// component is a symbolic link).
+    #[inline]
+    fn probe_symlinks(&self) -> bool {
+        self.flags
+            .intersects(RealpathFlags::NO_SYMLINKS | RealpathFlags::IGNORE_SYMLINKS)
+            || self.nofollow()
+    }
+
+    /// Whether the component being looked up should not be followed because of `NOFOLLOW_LAST`.
+    #[inline]
+    fn nofollow(&self) -> bool {
+        self.flags.contains(RealpathFlags::NOFOLLOW_LAST) && self.is_last_component()
+    }
+
+    /// Get the next component of the original path.
+    #[inline]
+    fn next_path_component(&mut self) -> Option<&'a [u8]> {
+        match self.logical.as_mut() {
+            Some(logical) => logical.next(&mut self.path_it),
+            None => self.path_it.next(),
+        }
+    }
+
+    /// Check whether there are no components of the original path left.
+    #[inline]
+    fn path_is_empty(&self) -> bool {
+        match self.logical.as_ref() {
+            Some(logical) => logical.is_empty(),
+            None => self.path_it.is_empty(),
+        }
+    }
+
+    /// Check whether the component being looked up is the last component of the original path
+    /// (and the path does not end with a slash, which requires it to be followed).
+    #[inline]
+    fn is_last_component(&self) -> bool {
+        !self.dir_required && self.stack.is_empty() && self.path_is_empty()
+    }
+
+    /// Check the device given in response to a `Device` request (with `NO_XDEV`).
+    fn check_xdev(&mut self) -> Result<(), i32> {
+        let dev = self.take_response()?;
+        if Some(dev) == self.dev {
+            Ok(())
+        } else {
+            Err(libc::EXDEV)
+        }
+    }
+
+    /// Run the algorithm until a request has to be made (returning `Some`), or until it finishes
+    /// (returning `None`).
+    fn advance<O: ResolveObserver + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<Option<Request>, i32> {
+        let in_root = self.flags.contains(RealpathFlags::IN_ROOT);
+        let beneath = self.flags.contains(RealpathFlags::BENEATH);
+
+        loop {
+            match self.state {
+                State::Start => {
+                    if self.flags.contains(RealpathFlags::NO_XDEV) {
+                        self.state = State::BaseDevice;
+                        return Ok(Some(Request::Device));
+                    }
+                    self.state = State::Next;
+                }
+
+                State::BaseDevice => {
+                    self.dev = Some(self.take_response()?);
+                    self.state = State::Next;
+                }
+
+                State::Next => {
+                    debug_assert_ne!(self.buf.as_ref(), b".");
+
+                    let (component, from_symlink) = match self.stack.next() {
+                        Some(component) => (component, true),
+                        None => {
+                            if self.pause_at == Some(self.path_len - self.path_it.as_bytes().len())
+                            {
+                                self.pause_at = None;
+                                return Ok(None);
+                            }
+
+                            match self.next_path_component() {
+                                Some(component) => (component, false),
+                                None => {
+                                    self.state = State::Finish;
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+
+                    let kind = match component {
+                        b"/" | b"//" => ComponentKind::Root,
+                        b".." => ComponentKind::Parent,
+                        _ => ComponentKind::Name,
+                    };
+                    self.current = Some(Current {
+                        kind,
+                        prefix_len: self.buf.len(),
+                        from_symlink,
+                    });
+                    self.components.spend(1)?;
+
+                    if component == b"/" || component == b"//" {
+                        if beneath {
+                            return Err(libc::EXDEV);
+                        }
+
+                        let double = component == b"//";
+                        if !in_root && self.dev.is_some() {
+                            self.state = State::Root { double };
+                            return Ok(Some(Request::Device));
+                        }
+
+                        self.buf
+                            .replace(if double && !in_root { b"//" } else { b"/" })?;
+                        self.existing_len = None;
+                        observer.root(&self.buf);
+                    } else if component == b".." {
+                        // With BENEATH, `buf` is always relative to the base directory ("" refers
+                        // to the base directory itself)
+                        if beneath && self.buf.is_empty() {
+                            return Err(libc::EXDEV);
+                        }
+                        self.buf.make_parent_path()?;
+                        if matches!(self.existing_len, Some(len) if self.buf.len() <= len) {
+                            // The missing component was removed
+                            self.existing_len = None;
+                        }
+
+                        if self.dev.is_some()
+                            && (self.buf.as_ref() == b".." || self.buf.ends_with(b"/.."))
+                        {
+                            // We've moved above the base directory, so this directory hasn't been
+                            // checked
+                            self.buf.push(b'\0')?;
+                            self.state = State::Parent;
+                            return Ok(Some(Request::Device));
+                        }
+
+                        observer.parent(&self.buf);
+                    } else {
+                        let oldlen = self.buf.len();
+
+                        if !matches!(self.buf.as_ref(), b"/" | b"//" | b"") {
+                            self.buf.push(b'/')?;
+                        }
+                        self.buf.extend_from_slice(component)?;
+                        self.buf.push(b'\0')?;
+
+                        self.state = State::Readlink { oldlen };
+                        return Ok(Some(Request::Readlink));
+                    }
+                }
+
+                State::Root { double } => {
+                    self.check_xdev()?;
+                    self.buf.replace(if double { b"//" } else { b"/" })?;
+                    self.existing_len = None;
+                    observer.root(&self.buf);
+                    self.state = State::Next;
+                }
+
+                State::Parent => {
+                    self.check_xdev()?;
+                    self.buf.pop();
+                    observer.parent(&self.buf);
+                    self.state = State::Next;
+                }
+
+                State::Readlink { oldlen } => {
+                    let res = self.take_response();
+
+                    let res = if self.nofollow() {
+                        // If NOFOLLOW_LAST was passed, make sure the last component exists, but
+                        // then act like it isn't a symlink if it is
+                        Err(res.err().unwrap_or(libc::EINVAL))
+                    } else if self.flags.contains(RealpathFlags::NO_SYMLINKS) {
+                        // If NO_SYMLINKS was passed, fail if it's a symlink
+                        match res {
+                            Ok(_) => return Err(libc::ELOOP),
+                            Err(eno) => Err(eno),
+                        }
+                    } else if self.flags.contains(RealpathFlags::IGNORE_SYMLINKS) {
+                        // If IGNORE_SYMLINKS was passed, make sure it exists, but then act like
+                        // it isn't a symlink if it is
+                        Err(res.err().unwrap_or(libc::EINVAL))
+                    } else {
+                        res
+                    };
+
+                    match res {
+                        Ok(_) => {
+                            #[cfg(any(target_os = "linux", target_os = "android"))]
+                            if util::may_be_magic_link(self.stack.top(), self.flags) {
+                                self.state = State::MagicLink { oldlen };
+                                return Ok(Some(Request::IsProcfs));
+                            }
+
+                            self.finish_symlink(oldlen, observer)?;
+                        }
+
+                        // Not a symlink
+                        Err(libc::EINVAL) => {
+                            if self.dev.is_some() {
+                                self.state = State::Component;
+                                return Ok(Some(Request::Device));
+                            }
+
+                            // Just remove the trailing NUL
+                            self.buf.pop();
+                            observer.component(&self.buf);
+                            self.state = State::Next;
+                        }
+
+                        // In these conditions, components of the path are allowed to not
+                        // exist/not be accessible/not be a directory
+                        Err(eno @ (libc::ENOENT | libc::EACCES | libc::ENOTDIR))
+                            if self.flags.contains(RealpathFlags::ALLOW_MISSING) =>
+                        {
+                            self.buf.pop();
+                            self.existing_len.get_or_insert(oldlen);
+                            observer.missing(&self.buf, eno);
+                            self.state = State::Next;
+                        }
+
+                        Err(libc::ENOENT)
+                            if self.flags.contains(RealpathFlags::ALLOW_LAST_MISSING)
+                                && self.stack.is_empty()
+                                && self.path_is_empty() =>
+                        {
+                            self.buf.pop();
+                            self.existing_len.get_or_insert(oldlen);
+                            observer.missing(&self.buf, libc::ENOENT);
+                            self.state = State::Next;
+                        }
+
+                        Err(eno) => return Err(eno),
+                    }
+                }
+
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                State::MagicLink { oldlen } => {
+                    if self.take_response()? != 0 {
+                        return Err(util::magic_link_error(self.flags));
+                    }
+                    self.finish_symlink(oldlen, observer)?;
+                }
+
+                State::Component => {
+                    self.check_xdev()?;
+                    self.buf.pop();
+                    observer.component(&self.buf);
+                    self.state = State::Next;
+                }
+
+                State::Finish => {
+                    self.current = Some(Current {
+                        kind: ComponentKind::Final,
+                        prefix_len: 0,
+                        from_symlink: false,
+                    });
+
+                    // If the original path ended with a slash, check that the result refers to a
+                    // directory (unless we already know it does)
+                    if self.dir_required
+                        && !self.flags.contains(RealpathFlags::ALLOW_MISSING)
+                        && !matches!(self.buf.as_ref(), b"/" | b"//")
+                        && !matches!(self.relative_parts(), Some((_, rest)) if rest.is_empty())
+                    {
+                        self.buf.push(b'\0')?;
+                        self.state = State::IsDir;
+                        return Ok(Some(Request::IsDir));
+                    }
+
+                    self.state = State::Cwd;
+                    if !self.buf.starts_with(b"/") {
+                        return Ok(Some(Request::Cwd));
+                    }
+                }
+
+                State::IsDir => {
+                    match self.take_response() {
+                        Ok(_) => (),
+                        Err(libc::ENOENT)
+                            if self.flags.contains(RealpathFlags::ALLOW_LAST_MISSING) => {}
+                        Err(eno) => return Err(eno),
+                    }
+                    self.buf.pop();
+
+                    self.state = State::Cwd;
+                    if !self.buf.starts_with(b"/") {
+                        return Ok(Some(Request::Cwd));
+                    }
+                }
+
+                State::Cwd => {
+                    self.current = None;
+                    if !self.buf.starts_with(b"/") {
+                        self.take_response()?;
+                        self.finish_relative(observer)?;
+                    }
+                    self.state = State::Done(self.buf.len());
+                }
+
+                State::Done(_) => return Ok(None),
+                State::Failed(eno) => return Err(eno),
+            }
+        }
+    }
+
+    /// Finish handling a symbolic link whose contents have been pushed onto the stack.
+    fn finish_symlink<O: ResolveObserver + ?Sized>(
+        &mut self,
+        oldlen: usize,
+        observer: &mut O,
+    ) -> Result<(), i32> {
+        #[cfg(feature = "std")]
+        if let Some(loops) = self.loops.as_mut() {
+            let link = &self.buf[..self.buf.len() - 1];
+            let id = self.link_id.take();
+            if loops.visit(link, id, self.stack.remaining(), self.path_it.as_bytes()) {
+                return Err(libc::ELOOP);
+            }
+        }
+
+        self.links.advance()?;
+        self.link_bytes.spend(self.stack.top().len())?;
+        observer.symlink(&self.buf[..self.buf.len() - 1], self.stack.top());
+        debug_assert!(self.buf.len() > oldlen);
+        self.buf.truncate(oldlen);
+        self.state = State::Next;
+        Ok(())
+    }
+
+    /// If `buf` is relative, split it into the number of leading `..` components and the rest of
+    /// the path.
+    fn relative_parts(&self) -> Option<(usize, &[u8])> {
+        if self.buf.starts_with(b"/") {
+            return None;
+        }
+
+        let n = count_leading_dotdot(&self.buf);
+        match &self.buf[(n * 3)..] {
+            b".." => Some((n + 1, b"")),
+            rest => Some((n, rest)),
+        }
+    }
+
+    /// Make a relative `buf` absolute by prepending the path to the current directory (which is
+    /// stored at the start of the stack's buffer).
+    fn finish_relative<O: ResolveObserver + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<(), i32> {
+        debug_assert!(!self.buf.starts_with(b"./"));
+        debug_assert_ne!(self.buf.as_ref(), b".");
+
+        let (n, rest_len) = match self.relative_parts() {
+            Some((n, rest)) => (n, rest.len()),
+            None => unreachable!(),
+        };
+
+        let mut cwd = SliceVec::empty(self.stack.clear());
+        cwd.set_len(self.cwd_len);
+        observer.cwd(&cwd);
+
+        for _ in 0..n {
+            cwd.make_parent_path()?;
+        }
+
+        if rest_len == 0 {
+            self.buf.replace(&cwd)?;
+        } else {
+            let rest_start = self.buf.len() - rest_len;
+            self.existing_len = self.existing_len.map(|len| {
+                if len <= rest_start {
+                    // Everything after the leading `..` components is missing
+                    cwd.len()
+                } else {
+                    len - rest_start + cwd.len() + (cwd.as_ref() != b"/") as usize
+                }
+            });
+
+            self.buf.remove_range(0..rest_start);
+            if cwd.as_ref() != b"/" {
+                cwd.push(b'/')?;
+            }
+            self.buf.insert_from_slice(0, &cwd)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn count_leading_dotdot(mut s: &[u8]) -> usize {
+    let mut n = 0;
+    while s.starts_with(b"../") {
+        n += 1;
+        s = &s[3..];
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_leading_dotdot() {
+        assert_eq!(count_leading_dotdot(b""), 0);
+        assert_eq!(count_leading_dotdot(b".."), 0);
+        assert_eq!(count_leading_dotdot(b"../a"), 1);
+        assert_eq!(count_leading_dotdot(b"../../a"), 2);
+        assert_eq!(count_leading_dotdot(b"../a/../b"), 1);
+    }
+}
@@ -1,11 +1,23 @@
+use core::ffi::CStr;
+
 use crate::slicevec::SliceVec;
+use crate::FsBackend;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::RealpathFlags;
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+))]
+use crate::PATH_MAX;
 
 #[inline]
 pub fn errno_get() -> i32 {
     errno::errno().0
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct SymlinkCounter {
     max: u16,
     cur: u16,
@@ -27,6 +39,16 @@ impl SymlinkCounter {
         }
     }
 
+    /// Create a counter that allows `max` symbolic links (or the system's limit if `max` is
+    /// `None`).
+    #[inline]
+    pub fn with_max(max: Option<u16>) -> Self {
+        match max {
+            Some(max) => Self { max, cur: 0 },
+            None => Self::new(),
+        }
+    }
+
     #[inline]
     pub fn advance(&mut self) -> Result<(), i32> {
         if self.cur >= self.max {
@@ -38,6 +60,52 @@ impl SymlinkCounter {
     }
 }
 
+/// Optional limits on the work done while resolving a path.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Limits {
+    /// The maximum number of symbolic links to follow (`None` for the system's limit)
+    pub symlinks: Option<u16>,
+    /// The maximum number of components to process
+    pub components: Option<usize>,
+    /// The maximum total length of the targets of the symbolic links that are followed
+    pub link_bytes: Option<usize>,
+}
+
+impl Limits {
+    #[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Counts down the amount of something that can still be used, failing with `ELOOP` once it runs
+/// out.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    remaining: usize,
+}
+
+impl Budget {
+    #[inline]
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            remaining: max.unwrap_or(usize::MAX),
+        }
+    }
+
+    #[inline]
+    pub fn spend(&mut self, n: usize) -> Result<(), i32> {
+        match self.remaining.checked_sub(n) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(())
+            }
+            None => Err(libc::ELOOP),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ComponentStack<'a> {
     buf: &'a mut [u8],
@@ -55,54 +123,65 @@ impl<'a> ComponentStack<'a> {
         self.i == self.buf.len()
     }
 
-    pub unsafe fn push_readlink(&mut self, path: *const u8) -> Result<(), i32> {
+    /// Get the raw contents of the stack (the components that have not been processed yet).
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn remaining(&self) -> &[u8] {
+        &self.buf[self.i..]
+    }
+
+    /// Read the symbolic link `path` with `backend`, and push its contents onto the stack.
+    pub fn push_readlink<B: FsBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        path: &CStr,
+    ) -> Result<(), i32> {
         if self.i == 0 {
             return Err(libc::ENAMETOOLONG);
         }
 
-        match libc::readlink(
-            path as *const _,
-            self.buf.as_mut_ptr() as *mut libc::c_char,
-            self.i,
-        ) {
-            -1 => Err(errno_get()),
-
-            len => {
-                debug_assert!(len > 0);
-
-                let len = len as usize;
-
-                // POSIX doesn't specify whether or not the returned string is nul-terminated.
-
-                // On OSes other than Linux/macOS/*BSD, it *might* be. Let's check.
-                #[cfg(not(any(
-                    target_os = "linux",
-                    target_os = "android",
-                    target_os = "freebsd",
-                    target_os = "dragonfly",
-                    target_os = "openbsd",
-                    target_os = "netbsd",
-                    target_os = "macos",
-                    target_os = "ios",
-                )))]
-                let len = if self.buf[len - 1] == 0 { len - 1 } else { len };
-
-                debug_assert_ne!(self.buf[len - 1], 0);
-
-                if len >= self.i - 1 {
-                    Err(libc::ENAMETOOLONG)
-                } else {
-                    self.i -= 1;
-                    self.buf[self.i] = 0;
-                    self.i -= len;
-                    self.buf.copy_within(0..len, self.i);
+        let len = backend.readlink(path, &mut self.buf[..self.i])?;
+        self.commit(len)
+    }
 
-                    Ok(())
-                }
-            }
+    /// Push `path` (the contents of a symbolic link) onto the stack.
+    pub fn push(&mut self, path: &[u8]) -> Result<(), i32> {
+        if path.len() > self.i {
+            return Err(libc::ENAMETOOLONG);
+        }
+
+        self.buf[..path.len()].copy_from_slice(path);
+        self.commit(path.len())
+    }
+
+    /// Move the `len` bytes at the start of the buffer onto the top of the stack.
+    fn commit(&mut self, len: usize) -> Result<(), i32> {
+        if len == 0 {
+            // Empty symlinks don't refer to anything
+            Err(libc::ENOENT)
+        } else if len >= self.i - 1 {
+            Err(libc::ENAMETOOLONG)
+        } else if self.buf[..len].contains(&0) {
+            // The backend returned bad data
+            Err(libc::EIO)
+        } else {
+            self.i -= 1;
+            self.buf[self.i] = 0;
+            self.i -= len;
+            self.buf.copy_within(0..len, self.i);
+
+            Ok(())
         }
     }
 
+    /// Get the path most recently pushed with [`Self::push_readlink()`].
+    ///
+    /// This is only valid if [`Self::next()`] has not been called since then.
+    pub fn top(&self) -> &[u8] {
+        let rest = &self.buf[self.i..];
+        &rest[..rest.iter().position(|&ch| ch == 0).unwrap_or(rest.len())]
+    }
+
     pub fn next(&mut self) -> Option<&[u8]> {
         macro_rules! skip_slashes_nul {
             ($self:expr) => {{
@@ -196,6 +275,12 @@ impl<'a> ComponentIter<'a> {
             _ => self.clone().next().is_none(),
         }
     }
+
+    /// Get the part of the path that has not been iterated over yet.
+    #[inline]
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
 }
 
 impl<'a> Iterator for ComponentIter<'a> {
@@ -250,9 +335,125 @@ impl<'a> Iterator for ComponentIter<'a> {
     }
 }
 
-pub unsafe fn check_isdir(path: *const u8) -> Result<(), i32> {
+/// Records which components of a path are cancelled out by a later `..` (and so are skipped,
+/// along with the `..`, as with [`RealpathFlags::LOGICAL`](crate::RealpathFlags::LOGICAL)).
+///
+/// This is computed in a single backward pass over the path, so iterating over the remaining
+/// components never has to scan ahead for a matching `..`.
+#[derive(Debug)]
+pub struct LogicalSkips<'a> {
+    /// Bit `i` is set if the `i`-th component from the end of the path (not counting the root) is
+    /// cancelled out
+    cancelled: &'a mut [u8],
+    /// The number of components (not counting the root) that have not been iterated over yet
+    left: usize,
+    /// The number of components that will still be returned by `next()`
+    logical_left: usize,
+    /// The number of skipped components whose `..` has not been reached yet
+    open: usize,
+}
+
+impl<'a> LogicalSkips<'a> {
+    /// Record the components of `path_it` that are cancelled out, using the start of `tmp` to
+    /// store them. Returns the rest of `tmp`.
+    pub fn new(path_it: &ComponentIter, tmp: &'a mut [u8]) -> Result<(Self, &'a mut [u8]), i32> {
+        // A path of length N has at most (N + 1) / 2 components, so N / 16 + 1 bytes is enough
+        let len = path_it.as_bytes().len() / 16 + 1;
+        if len > tmp.len() {
+            return Err(libc::ENAMETOOLONG);
+        }
+        let (cancelled, tmp) = tmp.split_at_mut(len);
+
+        let mut this = Self {
+            cancelled,
+            left: 0,
+            logical_left: 0,
+            open: 0,
+        };
+        this.reset(path_it);
+        Ok((this, tmp))
+    }
+
+    /// Recompute which components are cancelled out after `path_it` has been replaced with a
+    /// suffix of the original path.
+    pub fn reset(&mut self, path_it: &ComponentIter) {
+        let cancelled = &mut *self.cancelled;
+        cancelled.fill(0);
+
+        let (logical_left, left) =
+            count_logical(path_it.as_bytes(), |i| cancelled[i / 8] |= 1 << (i % 8));
+
+        self.left = left;
+        self.logical_left = logical_left;
+        self.open = 0;
+    }
+
+    /// Get the next component of `path_it` that is not cancelled out.
+    pub fn next<'p>(&mut self, path_it: &mut ComponentIter<'p>) -> Option<&'p [u8]> {
+        loop {
+            let component = path_it.next()?;
+
+            if !matches!(component, b"/" | b"//") {
+                self.left -= 1;
+
+                if component == b".." {
+                    // A `..` always matches the most recent skipped component (if there is one)
+                    if self.open > 0 {
+                        self.open -= 1;
+                        continue;
+                    }
+                } else if self.cancelled[self.left / 8] & (1 << (self.left % 8)) != 0 {
+                    self.open += 1;
+                    continue;
+                }
+            }
+
+            self.logical_left -= 1;
+            return Some(component);
+        }
+    }
+
+    /// Check whether `next()` would return any more components.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.logical_left == 0
+    }
+}
+
+/// Count the components of `path` that are not cancelled out by a later `..` (see
+/// [`LogicalSkips`]).
+///
+/// `cancelled` is called with the index (counting from the end of the path, and not counting the
+/// root) of each component that is cancelled out. Returns the number of components that are not
+/// cancelled out, and the total number of components (not counting the root).
+pub fn count_logical(path: &[u8], mut cancelled: impl FnMut(usize)) -> (usize, usize) {
+    let mut count = path.starts_with(b"/") as usize;
+    let mut total = 0;
+    // The number of `..` components seen so far that haven't cancelled out a component yet
+    let mut pending = 0usize;
+
+    for (i, component) in path
+        .rsplit(|&ch| ch == b'/')
+        .filter(|component| !matches!(*component, b"" | b"."))
+        .enumerate()
+    {
+        if component == b".." {
+            pending += 1;
+        } else if pending > 0 {
+            pending -= 1;
+            cancelled(i);
+        } else {
+            count += 1;
+        }
+        total = i + 1;
+    }
+
+    (count + pending, total)
+}
+
+pub unsafe fn check_isdir(dirfd: libc::c_int, path: *const u8) -> Result<(), i32> {
     let mut buf = core::mem::MaybeUninit::uninit();
-    if libc::stat(path as *const _, buf.as_mut_ptr()) < 0 {
+    if libc::fstatat(dirfd, path as *const _, buf.as_mut_ptr(), 0) < 0 {
         Err(errno_get())
     } else if buf.assume_init().st_mode & libc::S_IFMT == libc::S_IFDIR {
         Ok(())
@@ -261,15 +462,164 @@ pub unsafe fn check_isdir(path: *const u8) -> Result<(), i32> {
     }
 }
 
+/// Get the device that `path` resides on (without following symbolic links).
 #[inline]
-pub unsafe fn readlink_empty(path: *const u8) -> Result<(), i32> {
-    if libc::readlink(path as *const _, &mut 0, 1) < 0 {
+pub unsafe fn get_dev(dirfd: libc::c_int, path: *const u8) -> Result<libc::dev_t, i32> {
+    get_file_id(dirfd, path).map(|(dev, _)| dev)
+}
+
+/// Get the device and inode numbers of `path` (without following symbolic links).
+pub unsafe fn get_file_id(
+    dirfd: libc::c_int,
+    path: *const u8,
+) -> Result<(libc::dev_t, libc::ino_t), i32> {
+    let mut buf = core::mem::MaybeUninit::uninit();
+    if libc::fstatat(
+        dirfd,
+        path as *const _,
+        buf.as_mut_ptr(),
+        libc::AT_SYMLINK_NOFOLLOW,
+    ) < 0
+    {
+        Err(errno_get())
+    } else {
+        let st = buf.assume_init();
+        Ok((st.st_dev, st.st_ino))
+    }
+}
+
+/// Check whether `path` (which is not followed if it is a symbolic link) resides on a procfs
+/// filesystem.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub unsafe fn is_procfs(dirfd: libc::c_int, path: *const u8) -> Result<bool, i32> {
+    let fd = libc::openat(
+        dirfd,
+        path as *const _,
+        libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+    );
+    if fd < 0 {
+        return Err(errno_get());
+    }
+
+    let res = is_procfs_fd(fd);
+    libc::close(fd);
+    res
+}
+
+/// Check whether the file referred to by `fd` resides on a procfs filesystem.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_procfs_fd(fd: libc::c_int) -> Result<bool, i32> {
+    let mut buf = core::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::fstatfs(fd, buf.as_mut_ptr()) } < 0 {
         Err(errno_get())
+    } else {
+        #[allow(clippy::unnecessary_cast)]
+        Ok(unsafe { buf.assume_init() }.f_type as i64 == libc::PROC_SUPER_MAGIC as i64)
+    }
+}
+
+/// Check whether a symbolic link (whose contents are `target`) is a procfs "magic link" that
+/// should not be followed.
+///
+/// `is_procfs` should check whether the symbolic link resides on a procfs filesystem.
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub fn check_magic_link<F: FnOnce() -> Result<bool, i32>>(
+    target: &[u8],
+    flags: RealpathFlags,
+    is_procfs: F,
+) -> Result<(), i32> {
+    if may_be_magic_link(target, flags) && is_procfs()? {
+        Err(magic_link_error(flags))
     } else {
         Ok(())
     }
 }
 
+/// Check whether a symbolic link (whose contents are `target`) needs to be checked for residing
+/// on a procfs filesystem (see [`check_magic_link()`]).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn may_be_magic_link(target: &[u8], flags: RealpathFlags) -> bool {
+    // Magic links that refer to paths always have absolute targets, and the other kind have
+    // targets like "pipe:[1234]". Other symlinks in procfs (like /proc/self) have relative
+    // targets, so we only need to check the filesystem type in these two cases.
+    let not_path = !target.starts_with(b"/")
+        && target
+            .split(|&ch| ch == b'/')
+            .next()
+            .unwrap_or_default()
+            .contains(&b':');
+
+    not_path || (flags.contains(RealpathFlags::NO_MAGICLINKS) && target.starts_with(b"/"))
+}
+
+/// Get the error to fail with when a magic link is encountered.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub fn magic_link_error(flags: RealpathFlags) -> i32 {
+    if flags.contains(RealpathFlags::NO_MAGICLINKS) {
+        libc::ELOOP
+    } else {
+        libc::ENXIO
+    }
+}
+
+/// Read the contents of the symbolic link `path` (relative to `dirfd`) into `buf`, returning the
+/// number of bytes read.
+///
+/// As with `readlink()`, the contents are truncated if they do not fit in `buf`.
+pub unsafe fn readlinkat(
+    dirfd: libc::c_int,
+    path: *const u8,
+    buf: &mut [u8],
+) -> Result<usize, i32> {
+    match libc::readlinkat(
+        dirfd,
+        path as *const _,
+        buf.as_mut_ptr() as *mut libc::c_char,
+        buf.len(),
+    ) {
+        -1 => Err(errno_get()),
+
+        len => {
+            let len = len as usize;
+
+            // POSIX doesn't specify whether or not the returned string is nul-terminated.
+
+            // On OSes other than Linux/macOS/*BSD, it *might* be. Let's check.
+            #[cfg(not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "dragonfly",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "macos",
+                target_os = "ios",
+            )))]
+            let len = if len > 0 && buf[len - 1] == 0 {
+                len - 1
+            } else {
+                len
+            };
+
+            Ok(len)
+        }
+    }
+}
+
+/// Check whether `path` is a symbolic link (without reading all of its contents).
+#[inline]
+pub fn readlink_empty<B: FsBackend + ?Sized>(backend: &B, path: &CStr) -> Result<(), i32> {
+    backend.readlink(path, &mut [0]).map(drop)
+}
+
+/// Convert `s` (which must end with a NUL byte) to a `CStr`, failing with `EINVAL` if it contains
+/// any other NUL bytes.
+#[inline]
+pub fn cstr(s: &[u8]) -> Result<&CStr, i32> {
+    CStr::from_bytes_with_nul(s).map_err(|_| libc::EINVAL)
+}
+
 pub fn getcwd(buf: &mut SliceVec) -> Result<(), i32> {
     if buf.capacity() == 0 {
         // getcwd() is just going to fail with EINVAL; bail out early
@@ -300,6 +650,248 @@ pub fn getcwd(buf: &mut SliceVec) -> Result<(), i32> {
     }
 }
 
+/// Store the path to `backend`'s base directory in `buf` (replacing its current contents).
+///
+/// If `physical` is `true`, the path must not contain any symbolic links (see
+/// `FsBackend::getcwd_physical()`).
+pub fn backend_getcwd<B: FsBackend + ?Sized>(
+    backend: &B,
+    buf: &mut SliceVec,
+    physical: bool,
+) -> Result<(), i32> {
+    buf.set_len(buf.capacity());
+    let len = if physical {
+        backend.getcwd_physical(buf)?
+    } else {
+        backend.getcwd(buf)?
+    };
+    if len > buf.len() {
+        // The backend returned bad data
+        return Err(libc::EIO);
+    }
+    buf.set_len(len);
+
+    if buf.first() == Some(&b'/') {
+        Ok(())
+    } else {
+        Err(libc::ENOENT)
+    }
+}
+
+/// Get the path to the directory referred to by `dirfd`.
+///
+/// If `dirfd` is `AT_FDCWD`, this is equivalent to [`getcwd()`].
+pub fn getdirpath(dirfd: libc::c_int, buf: &mut SliceVec) -> Result<(), i32> {
+    if dirfd == libc::AT_FDCWD {
+        return getcwd(buf);
+    }
+
+    getfdpath(dirfd, buf)?;
+
+    if buf.first() != Some(&b'/') {
+        return Err(libc::ENOENT);
+    }
+
+    // Make sure that the path still refers to the same directory (it may have been renamed or
+    // removed)
+    buf.push(b'\0')?;
+    let same = unsafe { same_file(dirfd, buf.as_ptr()) };
+    buf.pop();
+
+    if same? {
+        Ok(())
+    } else {
+        Err(libc::ENOENT)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn getfdpath(fd: libc::c_int, buf: &mut SliceVec) -> Result<(), i32> {
+    // "/proc/self/fd/" plus enough room for any `c_int` and a trailing NUL
+    let mut proc_path = [0u8; 32];
+    let mut proc_path = SliceVec::empty(&mut proc_path);
+    proc_path.extend_from_slice(b"/proc/self/fd/")?;
+
+    if fd < 0 {
+        return Err(libc::EBADF);
+    }
+    let mut digits = [0u8; 10];
+    let mut n = fd as u32;
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    proc_path.extend_from_slice(&digits[i..])?;
+    proc_path.push(b'\0')?;
+
+    buf.set_len(buf.capacity());
+
+    match unsafe {
+        libc::readlink(
+            proc_path.as_ptr() as *const _,
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+        )
+    } {
+        -1 => Err(errno_get()),
+        len if len as usize >= buf.len() => Err(libc::ENAMETOOLONG),
+        len => {
+            buf.set_len(len as usize);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+))]
+fn getfdpath(fd: libc::c_int, buf: &mut SliceVec) -> Result<(), i32> {
+    // F_GETPATH assumes a buffer of at least MAXPATHLEN bytes
+    if buf.capacity() < PATH_MAX {
+        return Err(libc::ENAMETOOLONG);
+    }
+    buf.set_len(buf.capacity());
+
+    if unsafe { libc::fcntl(fd, libc::F_GETPATH, buf.as_mut_ptr()) } < 0 {
+        Err(errno_get())
+    } else if let Some(i) = buf.iter().position(|&ch| ch == 0) {
+        buf.set_len(i);
+        Ok(())
+    } else {
+        Err(libc::EIO)
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+)))]
+fn getfdpath(_fd: libc::c_int, _buf: &mut SliceVec) -> Result<(), i32> {
+    Err(libc::ENOTSUP)
+}
+
+// From <linux/openat2.h>
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Resolve `path` (relative to `dirfd`) using Linux's `openat2()`, then store the path to the
+/// opened file in `buf`.
+///
+/// `resolve` is passed as the `resolve` field of `struct open_how`. Fails with `ENOSYS` if
+/// `openat2()` is not available (or has been found to be unavailable by a previous call).
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub fn realpath_openat2(
+    dirfd: libc::c_int,
+    path: *const u8,
+    resolve: u64,
+    buf: &mut SliceVec,
+) -> Result<(), i32> {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    // Avoid repeatedly making system calls that will just fail
+    static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+    #[repr(C)]
+    struct OpenHow {
+        flags: u64,
+        mode: u64,
+        resolve: u64,
+    }
+
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return Err(libc::ENOSYS);
+    }
+
+    let how = OpenHow {
+        flags: (libc::O_PATH | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve,
+    };
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dirfd,
+            path,
+            &how as *const OpenHow,
+            core::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd < 0 {
+        let eno = errno_get();
+        // EPERM usually means that a seccomp filter is blocking it
+        if eno == libc::ENOSYS || eno == libc::EPERM {
+            UNSUPPORTED.store(true, Ordering::Relaxed);
+        }
+        return Err(eno);
+    }
+
+    let fd = fd as libc::c_int;
+    let res = getdirpath(fd, buf);
+    unsafe {
+        libc::close(fd);
+    }
+    res
+}
+
+/// Check whether `path` is a valid "logical" path to the current working directory: it must be
+/// absolute, contain no `.` or `..` components, and refer to the same file as `.` (this is the
+/// rule that `pwd -L` uses for `$PWD`).
+#[cfg(feature = "std")]
+pub fn is_logical_cwd(path: &CStr) -> bool {
+    let bytes = path.to_bytes();
+    if !bytes.starts_with(b"/")
+        || bytes
+            .split(|&ch| ch == b'/')
+            .any(|component| component == b"." || component == b"..")
+    {
+        return false;
+    }
+
+    let mut st1 = core::mem::MaybeUninit::uninit();
+    let mut st2 = core::mem::MaybeUninit::uninit();
+
+    unsafe {
+        if libc::stat(path.as_ptr(), st1.as_mut_ptr()) < 0
+            || libc::stat(b".\0".as_ptr() as *const _, st2.as_mut_ptr()) < 0
+        {
+            return false;
+        }
+
+        let (st1, st2) = (st1.assume_init(), st2.assume_init());
+        st1.st_dev == st2.st_dev && st1.st_ino == st2.st_ino
+    }
+}
+
+/// Check whether `path` refers to the same file as `fd`.
+unsafe fn same_file(fd: libc::c_int, path: *const u8) -> Result<bool, i32> {
+    let mut st1 = core::mem::MaybeUninit::uninit();
+    let mut st2 = core::mem::MaybeUninit::uninit();
+
+    if libc::fstat(fd, st1.as_mut_ptr()) < 0 || libc::stat(path as *const _, st2.as_mut_ptr()) < 0 {
+        return Err(errno_get());
+    }
+
+    let (st1, st2) = (st1.assume_init(), st2.assume_init());
+    Ok(st1.st_dev == st2.st_dev && st1.st_ino == st2.st_ino)
+}
+
 pub fn strip_leading_slashes(mut s: &[u8]) -> &[u8] {
     while let Some((&b'/', rest)) = s.split_first() {
         s = rest;
@@ -311,6 +903,27 @@ pub fn strip_leading_slashes(mut s: &[u8]) -> &[u8] {
 mod tests {
     use super::*;
 
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_is_logical_cwd() {
+        use std::os::unix::prelude::*;
+
+        let mut cwd = std::env::current_dir().unwrap().into_os_string().into_vec();
+        cwd.push(b'\0');
+        assert!(is_logical_cwd(cstr(&cwd).unwrap()));
+
+        assert!(!is_logical_cwd(cstr(b".\0").unwrap()));
+        assert!(!is_logical_cwd(cstr(b"/\0").unwrap()) || cwd == b"/\0");
+
+        cwd.pop();
+        for suffix in [&b"/."[..], b"/./", b"/x/.."].iter() {
+            let mut path = cwd.clone();
+            path.extend_from_slice(suffix);
+            path.push(b'\0');
+            assert!(!is_logical_cwd(cstr(&path).unwrap()));
+        }
+    }
+
     #[test]
     fn test_strip_leading_slashes() {
         assert_eq!(strip_leading_slashes(b""), b"");
@@ -354,12 +967,25 @@ mod tests {
         stack.i = stack.buf.len() - data.len();
         stack.buf[stack.i..].copy_from_slice(data);
 
+        assert_eq!(stack.top(), b"abc");
         assert_eq!(stack.next().unwrap(), b"abc");
         assert_eq!(stack.next(), None);
 
+        let mut buf = [0; 10];
+        let mut stack = ComponentStack::new(&mut buf);
+        stack.push(b"abc/def").unwrap();
+        assert_eq!(stack.top(), b"abc/def");
+        assert_eq!(stack.push(b"gh").unwrap_err(), libc::ENAMETOOLONG);
+        assert_eq!(stack.push(b"").unwrap_err(), libc::ENOENT);
+        stack.clear();
+        assert_eq!(stack.push(b"a\0").unwrap_err(), libc::EIO);
+        assert_eq!(stack.push(b"0123456789a").unwrap_err(), libc::ENAMETOOLONG);
+
         let mut stack = ComponentStack::new(&mut []);
         assert_eq!(
-            unsafe { stack.push_readlink(b"/\0".as_ptr()) }.unwrap_err(),
+            stack
+                .push_readlink(&crate::LibcBackend::new(), cstr(b"/\0").unwrap())
+                .unwrap_err(),
             libc::ENAMETOOLONG,
         );
     }
@@ -397,6 +1023,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_logical_skips() {
+        fn check(path: &[u8], res: &[&[u8]]) {
+            let mut tmp = [0xff; 16];
+            let mut it = ComponentIter::new(path).unwrap();
+            let (mut logical, _) = LogicalSkips::new(&it, &mut tmp).unwrap();
+            let mut res = res.iter();
+
+            assert_eq!(logical.is_empty(), res.len() == 0);
+            while let Some(component) = logical.next(&mut it) {
+                assert_eq!(res.next().cloned(), Some(component));
+                assert_eq!(logical.is_empty(), res.len() == 0);
+            }
+
+            assert!(logical.is_empty());
+            assert_eq!(res.len(), 0, "{:?}", res);
+        }
+
+        check(b"/", &[b"/"]);
+        check(b"//a/./b", &[b"//", b"a", b"b"]);
+        check(b"a/..", &[]);
+        check(b"/a/b/../c", &[b"/", b"a", b"c"]);
+        check(b"/a/b/../../c/", &[b"/", b"c"]);
+        check(b"/../a/..", &[b"/", b".."]);
+        check(b"a/../../b", &[b"..", b"b"]);
+        check(b"a/b/c/../d/../../e", &[b"a", b"e"]);
+        check(b"a/b/../c/../d/..", &[b"a"]);
+
+        let mut tmp = [0; 1];
+        let it = ComponentIter::new(b"a/b/c/d/e/f/g/h/i").unwrap();
+        assert_eq!(
+            LogicalSkips::new(&it, &mut tmp).unwrap_err(),
+            libc::ENAMETOOLONG
+        );
+    }
+
     #[test]
     fn test_getcwd_toolong() {
         assert_eq!(
@@ -409,26 +1071,121 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_getdirpath() {
+        let mut buf = [0; 4096];
+        let mut buf = SliceVec::empty(&mut buf);
+
+        let mut cwd = [0; 4096];
+        let mut cwd = SliceVec::empty(&mut cwd);
+        getcwd(&mut cwd).unwrap();
+
+        getdirpath(libc::AT_FDCWD, &mut buf).unwrap();
+        assert_eq!(buf.as_ref(), cwd.as_ref());
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let fd = unsafe { libc::open(b"/\0".as_ptr() as *const _, libc::O_RDONLY) };
+            assert!(fd >= 0);
+            getdirpath(fd, &mut buf).unwrap();
+            unsafe {
+                libc::close(fd);
+            }
+            assert_eq!(buf.as_ref(), b"/");
+
+            assert_eq!(getdirpath(-1, &mut buf).unwrap_err(), libc::EBADF);
+        }
+    }
+
+    #[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn test_realpath_openat2() {
+        let mut buf = [0; 4096];
+        let mut buf = SliceVec::empty(&mut buf);
+
+        match realpath_openat2(libc::AT_FDCWD, b"/\0".as_ptr(), 0, &mut buf) {
+            Ok(()) => assert_eq!(buf.as_ref(), b"/"),
+            // openat2() or /proc may not be available
+            Err(libc::ENOSYS) | Err(libc::ENOENT) => return,
+            Err(eno) => panic!("{}", eno),
+        }
+
+        assert_eq!(
+            realpath_openat2(libc::AT_FDCWD, b"/NOEXIST\0".as_ptr(), 0, &mut buf).unwrap_err(),
+            libc::ENOENT
+        );
+        assert_eq!(
+            realpath_openat2(libc::AT_FDCWD, b"/\0".as_ptr(), RESOLVE_BENEATH, &mut buf)
+                .unwrap_err(),
+            libc::EXDEV
+        );
+    }
+
     #[test]
     fn test_check_isdir() {
         unsafe {
-            assert_eq!(check_isdir(b"\0".as_ptr()).unwrap_err(), libc::ENOENT);
             assert_eq!(
-                check_isdir(b"/bin/sh\0".as_ptr()).unwrap_err(),
+                check_isdir(libc::AT_FDCWD, b"\0".as_ptr()).unwrap_err(),
+                libc::ENOENT
+            );
+            assert_eq!(
+                check_isdir(libc::AT_FDCWD, b"/bin/sh\0".as_ptr()).unwrap_err(),
                 libc::ENOTDIR
             );
-            check_isdir(b"/\0".as_ptr()).unwrap();
+            check_isdir(libc::AT_FDCWD, b"/\0".as_ptr()).unwrap();
         }
     }
 
     #[test]
-    fn test_readlink_empty() {
+    fn test_get_dev() {
+        unsafe {
+            assert_eq!(
+                get_dev(libc::AT_FDCWD, b"\0".as_ptr()).unwrap_err(),
+                libc::ENOENT
+            );
+            assert_eq!(
+                get_dev(libc::AT_FDCWD, b"/\0".as_ptr()).unwrap(),
+                get_dev(libc::AT_FDCWD, b"/.\0".as_ptr()).unwrap()
+            );
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_is_procfs() {
         unsafe {
-            assert_eq!(readlink_empty(b"\0".as_ptr()).unwrap_err(), libc::ENOENT);
-            assert_eq!(readlink_empty(b"/\0".as_ptr()).unwrap_err(), libc::EINVAL);
+            assert!(!is_procfs(libc::AT_FDCWD, b"/\0".as_ptr()).unwrap());
+            assert_eq!(
+                is_procfs(libc::AT_FDCWD, b"\0".as_ptr()).unwrap_err(),
+                libc::ENOENT
+            );
+            if is_procfs(libc::AT_FDCWD, b"/proc\0".as_ptr()).unwrap_or(false) {
+                // /proc/self/cwd itself is on procfs, even though it points elsewhere
+                assert!(is_procfs(libc::AT_FDCWD, b"/proc/self/cwd\0".as_ptr()).unwrap());
+            }
         }
     }
 
+    #[test]
+    fn test_readlink_empty() {
+        let backend = crate::LibcBackend::new();
+        assert_eq!(
+            readlink_empty(&backend, cstr(b"\0").unwrap()).unwrap_err(),
+            libc::ENOENT
+        );
+        assert_eq!(
+            readlink_empty(&backend, cstr(b"/\0").unwrap()).unwrap_err(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn test_cstr() {
+        assert_eq!(cstr(b"abc\0").unwrap().to_bytes(), b"abc");
+        assert_eq!(cstr(b"abc").unwrap_err(), libc::EINVAL);
+        assert_eq!(cstr(b"a\0bc\0").unwrap_err(), libc::EINVAL);
+    }
+
     #[test]
     fn test_symlinkcounter() {
         let mut links = SymlinkCounter::new();
@@ -439,5 +1196,25 @@ mod tests {
             links.advance().unwrap();
         }
         assert_eq!(links.advance(), Err(libc::ELOOP));
+
+        let mut links = SymlinkCounter::with_max(Some(1));
+        links.advance().unwrap();
+        assert_eq!(links.advance(), Err(libc::ELOOP));
+        assert_eq!(
+            SymlinkCounter::with_max(None).max,
+            SymlinkCounter::new().max
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut budget = Budget::new(Some(5));
+        budget.spend(2).unwrap();
+        budget.spend(3).unwrap();
+        budget.spend(0).unwrap();
+        assert_eq!(budget.spend(1), Err(libc::ELOOP));
+
+        let mut budget = Budget::new(None);
+        budget.spend(usize::MAX).unwrap();
     }
 }
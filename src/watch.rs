@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::os::unix::prelude::*;
+
+use crate::util;
+
+const MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_ATTRIB
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF
+    | libc::IN_ONLYDIR
+    | libc::IN_DONT_FOLLOW;
+
+/// What [`Watcher::read_events()`] found.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    /// The paths that have changed (changes to a directory may also affect everything beneath it)
+    pub paths: Vec<Vec<u8>>,
+    /// Whether any events were received at all
+    pub any: bool,
+    /// Whether the event queue overflowed (in which case anything may have changed)
+    pub overflow: bool,
+}
+
+/// Watches the parent directories of a set of absolute paths with inotify.
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    fd: OwnedFd,
+    /// Maps each watched directory to its watch descriptor and the number of paths inside it
+    dirs: BTreeMap<Box<[u8]>, (libc::c_int, usize)>,
+    /// Maps each watch descriptor to the directories it watches (there may be several if the same
+    /// directory is visible at multiple paths, e.g. because of bind mounts)
+    wds: BTreeMap<libc::c_int, Vec<Box<[u8]>>>,
+}
+
+fn parent(path: &[u8]) -> &[u8] {
+    match path.iter().rposition(|&ch| ch == b'/') {
+        Some(0) | None => b"/",
+        Some(i) => &path[..i],
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self, i32> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(util::errno_get());
+        }
+
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            dirs: BTreeMap::new(),
+            wds: BTreeMap::new(),
+        })
+    }
+
+    /// Start watching for changes to the absolute path `path` (by watching its parent directory).
+    ///
+    /// Every successful call must be balanced by a call to [`Self::release()`].
+    pub fn acquire(&mut self, path: &[u8]) -> Result<(), i32> {
+        let dir = parent(path);
+        if let Some((_, refs)) = self.dirs.get_mut(dir) {
+            *refs += 1;
+            return Ok(());
+        }
+
+        let mut cdir = Vec::with_capacity(dir.len() + 1);
+        cdir.extend_from_slice(dir);
+        cdir.push(0);
+        let cdir = util::cstr(&cdir)?;
+
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), cdir.as_ptr(), MASK) };
+        if wd < 0 {
+            return Err(util::errno_get());
+        }
+
+        self.dirs.insert(dir.into(), (wd, 1));
+        self.wds.entry(wd).or_default().push(dir.into());
+        Ok(())
+    }
+
+    /// Stop watching for changes to `path`.
+    pub fn release(&mut self, path: &[u8]) {
+        let dir = parent(path);
+        let wd = match self.dirs.get_mut(dir) {
+            Some((wd, refs)) => {
+                *refs -= 1;
+                if *refs > 0 {
+                    return;
+                }
+                *wd
+            }
+            // The watch was already removed by the kernel
+            None => return,
+        };
+        self.dirs.remove(dir);
+
+        if let Some(dirs) = self.wds.get_mut(&wd) {
+            dirs.retain(|d| &**d != dir);
+            if dirs.is_empty() {
+                self.wds.remove(&wd);
+                unsafe {
+                    libc::inotify_rm_watch(self.fd.as_raw_fd(), wd as _);
+                }
+            }
+        }
+    }
+
+    /// Stop watching everything.
+    pub fn clear(&mut self) {
+        for &wd in self.wds.keys() {
+            unsafe {
+                libc::inotify_rm_watch(self.fd.as_raw_fd(), wd as _);
+            }
+        }
+        self.dirs.clear();
+        self.wds.clear();
+    }
+
+    /// Read all pending events without blocking.
+    pub fn read_events(&mut self) -> Changes {
+        const HEADER: usize = core::mem::size_of::<libc::inotify_event>();
+
+        let mut changes = Changes::default();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n <= 0 {
+                if n < 0 && util::errno_get() == libc::EINTR {
+                    continue;
+                }
+                // Most likely EAGAIN (no more events)
+                return changes;
+            }
+            changes.any = true;
+
+            let buf = &buf[..n as usize];
+            let mut off = 0;
+            while off + HEADER <= buf.len() {
+                let event = unsafe {
+                    core::ptr::read_unaligned(buf[off..].as_ptr() as *const libc::inotify_event)
+                };
+                let name = &buf[off + HEADER..(off + HEADER + event.len as usize).min(buf.len())];
+                let name = &name[..name.iter().position(|&ch| ch == 0).unwrap_or(name.len())];
+                off += HEADER + event.len as usize;
+
+                self.handle_event(&event, name, &mut changes);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: &libc::inotify_event, name: &[u8], changes: &mut Changes) {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            changes.overflow = true;
+            return;
+        }
+
+        if event.mask & libc::IN_IGNORED != 0 {
+            // The kernel removed the watch (e.g. because the directory was deleted or unmounted),
+            // so we're blind to anything inside those directories now
+            if let Some(dirs) = self.wds.remove(&event.wd) {
+                for dir in dirs {
+                    self.dirs.remove(&dir);
+                    changes.paths.push(dir.into());
+                }
+            }
+            return;
+        }
+
+        let dirs = match self.wds.get(&event.wd) {
+            Some(dirs) => dirs,
+            // We already stopped watching this directory
+            None => return,
+        };
+
+        for dir in dirs {
+            let mut path = dir.to_vec();
+            if !name.is_empty() {
+                if path != b"/" {
+                    path.push(b'/');
+                }
+                path.extend_from_slice(name);
+            }
+            changes.paths.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parent() {
+        assert_eq!(parent(b"/"), b"/");
+        assert_eq!(parent(b"/a"), b"/");
+        assert_eq!(parent(b"/a/b"), b"/a");
+        assert_eq!(parent(b"/a/b/c"), b"/a/b");
+    }
+}
@@ -1,6 +1,9 @@
 use std::ffi::OsString;
 use std::fs;
 
+#[cfg(target_family = "unix")]
+use std::os::unix::prelude::*;
+
 use realpath_ext::RealpathFlags;
 
 #[cfg(feature = "std")]
@@ -10,7 +13,7 @@ use realpath_ext::realpath;
 fn realpath<P: AsRef<std::path::Path>>(
     path: P,
     flags: RealpathFlags,
-) -> std::io::Result<std::path::PathBuf> {
+) -> Result<std::path::PathBuf, realpath_ext::RealpathError> {
     #[cfg(target_family = "unix")]
     use std::os::unix::prelude::*;
     #[cfg(target_os = "wasi")]
@@ -18,8 +21,7 @@ fn realpath<P: AsRef<std::path::Path>>(
 
     let mut buf = vec![0; libc::PATH_MAX as usize];
 
-    let n = realpath_ext::realpath_raw(path.as_ref().as_os_str().as_bytes(), &mut buf, flags)
-        .map_err(std::io::Error::from_raw_os_error)?;
+    let n = realpath_ext::realpath_raw(path.as_ref().as_os_str().as_bytes(), &mut buf, flags)?;
 
     buf.truncate(n);
     Ok(OsString::from_vec(buf).into())
@@ -226,7 +228,9 @@ fn test_builders() {
     // Resolving a path with a buffer that's too short should fail
     let mut buf = vec![0u8; 10];
     assert_eq!(
-        realpath_ext::RealpathRawBuilder::default().realpath_raw(b"/etc/passwd", &mut buf),
+        realpath_ext::RealpathRawBuilder::default()
+            .realpath_raw(b"/etc/passwd", &mut buf)
+            .map_err(|e| e.errno()),
         Err(libc::ENAMETOOLONG),
     );
 
@@ -239,9 +243,2197 @@ fn test_builders() {
             assert_eq!(
                 realpath_ext::RealpathRawBuilder::new()
                     .temp_buffer(Some(&mut tmp))
-                    .realpath_raw(path.as_bytes(), &mut buf),
+                    .realpath_raw(path.as_bytes(), &mut buf)
+                    .map_err(|e| e.errno()),
                 Err(libc::ENAMETOOLONG),
             );
         }
     }
 }
+
+#[test]
+fn test_realpathat() {
+    let cwd = std::env::current_dir().unwrap();
+    let root = fs::File::open("/").unwrap();
+    let src = fs::File::open("src").unwrap();
+
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+    for &(fd, path, expected) in [
+        (root.as_raw_fd(), "etc/passwd", "/etc/passwd".as_ref()),
+        (root.as_raw_fd(), ".", "/".as_ref()),
+        (root.as_raw_fd(), "..", "/".as_ref()),
+        (root.as_raw_fd(), "/etc/passwd", "/etc/passwd".as_ref()),
+        (src.as_raw_fd(), "..", cwd.as_path()),
+        (src.as_raw_fd(), ".", cwd.join("src").as_path()),
+        (src.as_raw_fd(), "lib.rs", cwd.join("src/lib.rs").as_path()),
+        (
+            src.as_raw_fd(),
+            "../src/./util.rs",
+            cwd.join("src/util.rs").as_path(),
+        ),
+        (
+            libc::AT_FDCWD,
+            "src/lib.rs",
+            cwd.join("src/lib.rs").as_path(),
+        ),
+    ]
+    .iter()
+    {
+        let n = realpath_ext::realpathat(fd, path.as_bytes(), &mut buf, RealpathFlags::empty())
+            .unwrap();
+        assert_eq!(
+            &buf[..n],
+            fs::canonicalize(expected).unwrap().as_os_str().as_bytes()
+        );
+    }
+
+    assert_eq!(
+        realpath_ext::realpathat(
+            src.as_raw_fd(),
+            b"NOEXIST",
+            &mut buf,
+            RealpathFlags::empty()
+        )
+        .map_err(|e| e.errno()),
+        Err(libc::ENOENT),
+    );
+    assert_eq!(
+        realpath_ext::realpathat(-1, b"a", &mut buf, RealpathFlags::ALLOW_MISSING)
+            .map_err(|e| e.errno()),
+        Err(libc::EBADF),
+    );
+
+    #[cfg(feature = "std")]
+    assert_eq!(
+        realpath_ext::RealpathBuilder::new()
+            .base_dir_fd(Some(src.as_raw_fd()))
+            .realpath("lib.rs")
+            .unwrap(),
+        fs::canonicalize("src/lib.rs").unwrap(),
+    );
+
+    let n = realpath_ext::RealpathRawBuilder::new()
+        .base_dir_fd(Some(root.as_raw_fd()))
+        .realpath_raw(b"etc/passwd", &mut buf)
+        .unwrap();
+    assert_eq!(
+        &buf[..n],
+        fs::canonicalize("/etc/passwd")
+            .unwrap()
+            .as_os_str()
+            .as_bytes()
+    );
+}
+
+fn make_tmpdir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("realpath-ext-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    fs::canonicalize(dir).unwrap()
+}
+
+#[test]
+fn test_in_root() {
+    let tmpdir = make_tmpdir("in-root");
+
+    fs::create_dir(tmpdir.join("b")).unwrap();
+    fs::write(tmpdir.join("b/file"), b"").unwrap();
+    std::os::unix::fs::symlink("/b", tmpdir.join("a")).unwrap();
+    std::os::unix::fs::symlink("../../..", tmpdir.join("b/up")).unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", tmpdir.join("passwd")).unwrap();
+
+    let root = fs::File::open(&tmpdir).unwrap();
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+    for &(path, expected) in [
+        ("/", "/"),
+        ("//", "/"),
+        (".", "/"),
+        ("..", "/"),
+        ("/../..", "/"),
+        ("a", "/b"),
+        ("/a/file", "/b/file"),
+        ("a/up", "/"),
+        ("a/up/b/../a", "/b"),
+    ]
+    .iter()
+    {
+        let n = realpath_ext::realpathat(
+            root.as_raw_fd(),
+            path.as_bytes(),
+            &mut buf,
+            RealpathFlags::IN_ROOT,
+        )
+        .unwrap();
+        assert_eq!(&buf[..n], expected.as_bytes(), "{:?}", path);
+    }
+
+    // /etc/passwd does not exist inside the root
+    assert_eq!(
+        realpath_ext::realpathat(
+            root.as_raw_fd(),
+            b"passwd",
+            &mut buf,
+            RealpathFlags::IN_ROOT
+        )
+        .map_err(|e| e.errno()),
+        Err(libc::ENOENT),
+    );
+    let n = realpath_ext::realpathat(
+        root.as_raw_fd(),
+        b"passwd",
+        &mut buf,
+        RealpathFlags::IN_ROOT | RealpathFlags::ALLOW_MISSING,
+    )
+    .unwrap();
+    assert_eq!(&buf[..n], b"/etc/passwd");
+
+    #[cfg(feature = "std")]
+    {
+        let mut builder = realpath_ext::RealpathBuilder::new();
+        builder.root_dir(Some(tmpdir.clone()));
+
+        assert_eq!(builder.realpath("a/file").unwrap().as_os_str(), "/b/file");
+        assert_eq!(
+            builder.realpath_in_root("/a/up/a").unwrap(),
+            ("/b".into(), tmpdir.join("b"))
+        );
+        assert_eq!(
+            builder.realpath_in_root("..").unwrap(),
+            ("/".into(), tmpdir.clone())
+        );
+
+        let (in_root, host) = realpath_ext::RealpathBuilder::new()
+            .base_dir_fd(Some(root.as_raw_fd()))
+            .realpath_in_root("a/up/a/file")
+            .unwrap();
+        assert_eq!(in_root.as_os_str(), "/b/file");
+        assert_eq!(host, tmpdir.join("b/file"));
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[test]
+fn test_beneath() {
+    let tmpdir = make_tmpdir("beneath");
+
+    fs::create_dir(tmpdir.join("b")).unwrap();
+    std::os::unix::fs::symlink("..", tmpdir.join("b/up")).unwrap();
+    std::os::unix::fs::symlink("..", tmpdir.join("up")).unwrap();
+    std::os::unix::fs::symlink("/etc", tmpdir.join("etc")).unwrap();
+
+    let base = fs::File::open(&tmpdir).unwrap();
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+    for &(path, expected) in [
+        (".", ""),
+        ("b", "/b"),
+        ("b/..", ""),
+        ("b/up", ""),
+        ("b/up/b/./up/b", "/b"),
+    ]
+    .iter()
+    {
+        let n = realpath_ext::realpathat(
+            base.as_raw_fd(),
+            path.as_bytes(),
+            &mut buf,
+            RealpathFlags::BENEATH,
+        )
+        .unwrap();
+        let mut expected_path = tmpdir.clone().into_os_string();
+        expected_path.push(expected);
+        assert_eq!(&buf[..n], expected_path.as_bytes(), "{:?}", path);
+    }
+
+    for &path in [
+        "..",
+        "b/../..",
+        "/",
+        "//",
+        "/etc",
+        "up",
+        "etc",
+        "b/up/up",
+        "NOEXIST/../..",
+    ]
+    .iter()
+    {
+        assert_eq!(
+            realpath_ext::realpathat(
+                base.as_raw_fd(),
+                path.as_bytes(),
+                &mut buf,
+                RealpathFlags::BENEATH | RealpathFlags::ALLOW_MISSING,
+            )
+            .map_err(|e| e.errno()),
+            Err(libc::EXDEV),
+            "{:?}",
+            path
+        );
+    }
+
+    assert_eq!(
+        realpath_ext::realpathat(
+            base.as_raw_fd(),
+            b"b",
+            &mut buf,
+            RealpathFlags::BENEATH | RealpathFlags::IN_ROOT,
+        )
+        .map_err(|e| e.errno()),
+        Err(libc::EINVAL),
+    );
+
+    #[cfg(feature = "std")]
+    assert_eq!(
+        realpath_ext::RealpathBuilder::new()
+            .base_dir_fd(Some(base.as_raw_fd()))
+            .flags(RealpathFlags::BENEATH)
+            .realpath("etc")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EXDEV),
+    );
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[test]
+fn test_no_xdev() {
+    let cwd_dev = fs::metadata(".").unwrap().dev();
+
+    realpath("src/lib.rs", RealpathFlags::NO_XDEV).unwrap();
+    realpath(
+        "NOEXIST",
+        RealpathFlags::NO_XDEV | RealpathFlags::ALLOW_MISSING,
+    )
+    .unwrap();
+
+    // Absolute paths start from the root directory, wherever the current directory is
+    let root_dev = fs::metadata("/").unwrap().dev();
+    for &path in ["/", "/proc/self", "/dev/null", "/sys/kernel"].iter() {
+        match fs::metadata(path) {
+            Ok(meta) if meta.dev() != root_dev => {
+                assert_eq!(
+                    realpath(path, RealpathFlags::NO_XDEV)
+                        .unwrap_err()
+                        .raw_os_error(),
+                    Some(libc::EXDEV),
+                    "{:?}",
+                    path
+                );
+            }
+            Ok(_) => {
+                realpath(path, RealpathFlags::NO_XDEV).unwrap();
+            }
+            Err(_) => (),
+        }
+    }
+
+    // /proc/self/cwd points back to the current directory, which is on a different device
+    if let Ok(proc) = fs::File::open("/proc") {
+        if proc.metadata().unwrap().dev() != cwd_dev {
+            let mut buf = vec![0u8; libc::PATH_MAX as usize];
+            let n = realpath_ext::realpathat(
+                proc.as_raw_fd(),
+                b"self",
+                &mut buf,
+                RealpathFlags::NO_XDEV,
+            )
+            .unwrap();
+            assert_eq!(
+                &buf[..n],
+                format!("/proc/{}", std::process::id()).as_bytes()
+            );
+
+            assert_eq!(
+                realpath_ext::realpathat(
+                    proc.as_raw_fd(),
+                    b"self/cwd",
+                    &mut buf,
+                    RealpathFlags::NO_XDEV
+                )
+                .map_err(|e| e.errno()),
+                Err(libc::EXDEV),
+            );
+        }
+
+        // Even if the base directory is on a different device, absolute paths are checked
+        // against the root directory
+        if proc.metadata().unwrap().dev() != root_dev {
+            let mut buf = vec![0u8; libc::PATH_MAX as usize];
+            let n =
+                realpath_ext::realpathat(proc.as_raw_fd(), b"/", &mut buf, RealpathFlags::NO_XDEV)
+                    .unwrap();
+            assert_eq!(&buf[..n], b"/");
+        }
+    }
+}
+
+#[test]
+fn test_no_symlinks() {
+    let tmpdir = make_tmpdir("no-symlinks");
+
+    fs::create_dir(tmpdir.join("dir")).unwrap();
+    std::os::unix::fs::symlink("dir", tmpdir.join("link")).unwrap();
+
+    realpath(tmpdir.join("dir"), RealpathFlags::NO_SYMLINKS).unwrap();
+    realpath(
+        tmpdir.join("dir/NOEXIST"),
+        RealpathFlags::NO_SYMLINKS | RealpathFlags::ALLOW_LAST_MISSING,
+    )
+    .unwrap();
+
+    for &path in ["link", "link/", "link/NOEXIST", "dir/../link/."].iter() {
+        for &flags in [
+            RealpathFlags::NO_SYMLINKS,
+            RealpathFlags::NO_SYMLINKS | RealpathFlags::IGNORE_SYMLINKS,
+            RealpathFlags::NO_SYMLINKS | RealpathFlags::ALLOW_MISSING,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                realpath(tmpdir.join(path), flags)
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(libc::ELOOP),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_magic_links() {
+    if !std::path::Path::new("/proc/self/fd").exists() {
+        return;
+    }
+
+    let file = fs::File::open("src/lib.rs").unwrap();
+    let fd_path = format!("/proc/self/fd/{}", file.as_raw_fd());
+    assert_eq!(
+        realpath(&fd_path, RealpathFlags::empty()).unwrap(),
+        fs::canonicalize("src/lib.rs").unwrap()
+    );
+    assert_eq!(
+        realpath(&fd_path, RealpathFlags::NO_MAGICLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    assert_eq!(
+        realpath("/proc/self/cwd", RealpathFlags::NO_MAGICLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+
+    // Ordinary symlinks in procfs are still followed
+    assert_eq!(
+        realpath("/proc/self", RealpathFlags::NO_MAGICLINKS).unwrap(),
+        fs::canonicalize("/proc/self").unwrap()
+    );
+
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let pipe_path = format!("/proc/self/fd/{}", fds[0]);
+
+    for &(flags, eno) in [
+        (RealpathFlags::empty(), libc::ENXIO),
+        (RealpathFlags::ALLOW_MISSING, libc::ENXIO),
+        (RealpathFlags::NO_MAGICLINKS, libc::ELOOP),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            realpath(&pipe_path, flags).unwrap_err().raw_os_error(),
+            Some(eno)
+        );
+    }
+
+    unsafe {
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_openat2() {
+    let tmpdir = make_tmpdir("openat2");
+    std::os::unix::fs::symlink("..", tmpdir.join("up")).unwrap();
+    std::os::unix::fs::symlink("/etc", tmpdir.join("etc")).unwrap();
+    let tmpdir_file = fs::File::open(&tmpdir).unwrap();
+
+    let mut fast = realpath_ext::RealpathBuilder::new();
+    fast.use_openat2(true);
+    let slow = realpath_ext::RealpathBuilder::new();
+
+    for path in [
+        "/".as_ref(),
+        "//".as_ref(),
+        ".".as_ref(),
+        "..".as_ref(),
+        "src/lib.rs".as_ref(),
+        "src/lib.rs/".as_ref(),
+        "NOEXIST".as_ref(),
+        "NOEXIST/a".as_ref(),
+        "/proc/self/cwd".as_ref(),
+        tmpdir.join("up").as_path(),
+        tmpdir.join("etc/passwd").as_path(),
+    ]
+    .iter()
+    {
+        for &flags in [
+            RealpathFlags::empty(),
+            RealpathFlags::ALLOW_LAST_MISSING,
+            RealpathFlags::ALLOW_MISSING,
+            RealpathFlags::NO_SYMLINKS,
+            RealpathFlags::NO_MAGICLINKS,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                fast.clone()
+                    .flags(flags)
+                    .realpath(path)
+                    .map_err(|e| e.raw_os_error()),
+                slow.clone()
+                    .flags(flags)
+                    .realpath(path)
+                    .map_err(|e| e.raw_os_error()),
+                "{:?} {:?}",
+                path,
+                flags
+            );
+        }
+    }
+
+    for &path in ["up", "etc", "."].iter() {
+        assert_eq!(
+            fast.clone()
+                .base_dir_fd(Some(tmpdir_file.as_raw_fd()))
+                .flags(RealpathFlags::BENEATH)
+                .realpath(path)
+                .map_err(|e| e.raw_os_error()),
+            slow.clone()
+                .base_dir_fd(Some(tmpdir_file.as_raw_fd()))
+                .flags(RealpathFlags::BENEATH)
+                .realpath(path)
+                .map_err(|e| e.raw_os_error()),
+            "{:?}",
+            path
+        );
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+#[test]
+fn test_realpath_open() {
+    fn check_same(fd: &OwnedFd, path: &std::path::Path) {
+        let mut st = std::mem::MaybeUninit::uninit();
+        assert_eq!(unsafe { libc::fstat(fd.as_raw_fd(), st.as_mut_ptr()) }, 0);
+        let st = unsafe { st.assume_init() };
+        let meta = fs::symlink_metadata(path).unwrap();
+        assert_eq!((st.st_dev, st.st_ino), (meta.dev(), meta.ino()));
+        assert_eq!(
+            unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) } & libc::O_PATH,
+            libc::O_PATH
+        );
+    }
+
+    let tmpdir = make_tmpdir("realpath-open");
+    fs::create_dir(tmpdir.join("b")).unwrap();
+    fs::write(tmpdir.join("b/file"), b"").unwrap();
+    std::os::unix::fs::symlink("/b", tmpdir.join("abs")).unwrap();
+    std::os::unix::fs::symlink("b/../b/file", tmpdir.join("rel")).unwrap();
+    std::os::unix::fs::symlink("..", tmpdir.join("up")).unwrap();
+    std::os::unix::fs::symlink("b/missing", tmpdir.join("dangling")).unwrap();
+
+    let builder = realpath_ext::RealpathBuilder::new();
+
+    for path in [
+        "/".as_ref(),
+        ".".as_ref(),
+        "..".as_ref(),
+        "src/lib.rs".as_ref(),
+        "src/../src/".as_ref(),
+        tmpdir.join("rel").as_path(),
+        tmpdir.join("up/.").as_path(),
+    ]
+    .iter()
+    {
+        let (resolved, fd) = builder.realpath_open(path).unwrap();
+        assert_eq!(resolved, fs::canonicalize(path).unwrap());
+        check_same(&fd, &resolved);
+    }
+
+    for &(path, eno) in [
+        ("NOEXIST", libc::ENOENT),
+        ("src/lib.rs/", libc::ENOTDIR),
+        ("src/lib.rs/..", libc::ENOTDIR),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            builder.realpath_open(path).unwrap_err().raw_os_error(),
+            Some(eno),
+        );
+    }
+
+    // Errors record where resolution failed
+    let cwd = std::env::current_dir().unwrap();
+    for (path, prefix, component, from_symlink) in [
+        (tmpdir.join("b/nope/x"), tmpdir.join("b"), "nope", false),
+        (tmpdir.join("dangling"), tmpdir.join("b"), "missing", true),
+        ("src/lib.rs/".into(), cwd.join("src"), "lib.rs", false),
+        ("src/lib.rs/..".into(), cwd.join("src/lib.rs"), "..", false),
+    ]
+    .iter()
+    {
+        let err = builder.realpath_open(path).unwrap_err();
+        assert_eq!(
+            err.prefix(),
+            Some(prefix.as_os_str().as_bytes()),
+            "{:?}",
+            path
+        );
+        assert_eq!(err.component(), Some(component.as_bytes()), "{:?}", path);
+        assert_eq!(err.from_symlink(), *from_symlink, "{:?}", path);
+    }
+
+    assert_eq!(
+        builder
+            .clone()
+            .flags(RealpathFlags::ALLOW_MISSING)
+            .realpath_open("src")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EINVAL),
+    );
+    assert_eq!(
+        builder
+            .clone()
+            .flags(RealpathFlags::NO_SYMLINKS)
+            .realpath_open(tmpdir.join("rel"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP),
+    );
+
+    // Inside a root
+    let (resolved, fd) = builder
+        .clone()
+        .root_dir(Some(tmpdir.clone()))
+        .realpath_open("up/abs/../rel")
+        .unwrap();
+    assert_eq!(resolved.as_os_str(), "/b/file");
+    check_same(&fd, &tmpdir.join("b/file"));
+
+    let tmpdir_file = fs::File::open(&tmpdir).unwrap();
+    let mut beneath = builder.clone();
+    beneath
+        .base_dir_fd(Some(tmpdir_file.as_raw_fd()))
+        .flags(RealpathFlags::BENEATH);
+    let (resolved, fd) = beneath.realpath_open("b/../rel").unwrap();
+    assert_eq!(resolved, tmpdir.join("b/file"));
+    check_same(&fd, &resolved);
+    for &path in ["up", "abs", "b/../.."].iter() {
+        assert_eq!(
+            beneath.realpath_open(path).unwrap_err().raw_os_error(),
+            Some(libc::EXDEV),
+        );
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[derive(Debug, Default)]
+struct Recorder(Vec<String>);
+
+impl realpath_ext::ResolveObserver for Recorder {
+    fn component(&mut self, path: &[u8]) {
+        self.0
+            .push(format!("component {}", String::from_utf8_lossy(path)));
+    }
+
+    fn parent(&mut self, path: &[u8]) {
+        self.0
+            .push(format!("parent {}", String::from_utf8_lossy(path)));
+    }
+
+    fn root(&mut self, path: &[u8]) {
+        self.0
+            .push(format!("root {}", String::from_utf8_lossy(path)));
+    }
+
+    fn symlink(&mut self, path: &[u8], target: &[u8]) {
+        self.0.push(format!(
+            "symlink {} -> {}",
+            String::from_utf8_lossy(path),
+            String::from_utf8_lossy(target)
+        ));
+    }
+
+    fn cwd(&mut self, cwd: &[u8]) {
+        self.0.push(format!("cwd {}", String::from_utf8_lossy(cwd)));
+    }
+
+    fn missing(&mut self, path: &[u8], errno: i32) {
+        self.0.push(format!(
+            "missing {} {}",
+            String::from_utf8_lossy(path),
+            errno
+        ));
+    }
+}
+
+#[test]
+fn test_observer() {
+    let tmpdir = make_tmpdir("observer");
+    fs::create_dir(tmpdir.join("b")).unwrap();
+    std::os::unix::fs::symlink("b/../b", tmpdir.join("a")).unwrap();
+    std::os::unix::fs::symlink("/b", tmpdir.join("abs")).unwrap();
+
+    let root = fs::File::open(&tmpdir).unwrap();
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+    let mut recorder = Recorder::default();
+    let n = realpath_ext::RealpathRawBuilder::new()
+        .base_dir_fd(Some(root.as_raw_fd()))
+        .flags(RealpathFlags::IN_ROOT | RealpathFlags::ALLOW_MISSING)
+        .realpath_raw_observed(b"abs/../a/x", &mut buf, &mut recorder)
+        .unwrap();
+    assert_eq!(&buf[..n], b"/b/x");
+    assert_eq!(
+        recorder.0,
+        [
+            "symlink /abs -> /b",
+            "root /",
+            "component /b",
+            "parent /",
+            "symlink /a -> b/../b",
+            "component /b",
+            "parent /",
+            "component /b",
+            &format!("missing /b/x {}", libc::ENOENT),
+        ]
+    );
+
+    let mut recorder = Recorder::default();
+    let n = realpath_ext::RealpathRawBuilder::new()
+        .base_dir_fd(Some(root.as_raw_fd()))
+        .realpath_raw_observed(b"b/..", &mut buf, &mut recorder)
+        .unwrap();
+    assert_eq!(&buf[..n], tmpdir.as_os_str().as_bytes());
+    assert_eq!(
+        recorder.0,
+        [
+            "component b".to_string(),
+            "parent ".to_string(),
+            format!("cwd {}", tmpdir.display()),
+        ]
+    );
+
+    #[cfg(feature = "std")]
+    {
+        let mut recorder = Recorder::default();
+        let path = realpath_ext::RealpathBuilder::new()
+            .realpath_observed(tmpdir.join("a"), &mut recorder)
+            .unwrap();
+        assert_eq!(path, tmpdir.join("b"));
+        assert!(recorder
+            .0
+            .contains(&format!("symlink {} -> b/../b", tmpdir.join("a").display())));
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+/// A tiny filesystem with the base directory at `/base`.
+struct FixtureBackend;
+
+impl FixtureBackend {
+    fn lookup(path: &std::ffi::CStr) -> Result<Option<&'static [u8]>, i32> {
+        let path = path.to_bytes();
+        let path = if path.starts_with(b"/") {
+            path.to_vec()
+        } else {
+            [b"/base/", path].concat()
+        };
+        let mut buf = [0; 100];
+        let n = realpath_ext::normpath_raw(&path, &mut buf).unwrap();
+
+        match &buf[..n] {
+            b"/" | b"/base" | b"/base/d" | b"/real" => Ok(None),
+            b"/base/l" => Ok(Some(b"/real")),
+            b"/base/d/up" => Ok(Some(b"..")),
+            b"/real/file" => Ok(Some(b"")),
+            _ => Err(libc::ENOENT),
+        }
+    }
+}
+
+impl realpath_ext::FsBackend for FixtureBackend {
+    fn readlink(&self, path: &std::ffi::CStr, buf: &mut [u8]) -> Result<usize, i32> {
+        match Self::lookup(path)? {
+            Some(target) if !target.is_empty() => {
+                let n = target.len().min(buf.len());
+                buf[..n].copy_from_slice(&target[..n]);
+                Ok(n)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn check_isdir(&self, path: &std::ffi::CStr) -> Result<(), i32> {
+        match Self::lookup(path)? {
+            None => Ok(()),
+            Some(_) => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        buf.get_mut(..5)
+            .ok_or(libc::ENAMETOOLONG)?
+            .copy_from_slice(b"/base");
+        Ok(5)
+    }
+}
+
+#[test]
+fn test_backend() {
+    let backend = FixtureBackend;
+    let mut buf = [0; 100];
+
+    let mut builder = realpath_ext::RealpathRawBuilder::new();
+    builder.backend(Some(&backend));
+
+    let n = builder.realpath_raw(b".", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/base");
+    let n = builder.realpath_raw(b"d/up/l/", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/real");
+    let n = builder
+        .realpath_raw(b"/base/d/../d/up/../..", &mut buf)
+        .unwrap();
+    assert_eq!(&buf[..n], b"/");
+    assert_eq!(
+        builder
+            .realpath_raw(b"l/x", &mut buf)
+            .map_err(|e| e.errno()),
+        Err(libc::ENOENT)
+    );
+    assert_eq!(
+        builder
+            .realpath_raw(b"l/file/", &mut buf)
+            .map_err(|e| e.errno()),
+        Err(libc::ENOTDIR)
+    );
+
+    builder.flags(RealpathFlags::ALLOW_MISSING);
+    let n = builder.realpath_raw(b"l/x/y", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/real/x/y");
+
+    builder.flags(RealpathFlags::IN_ROOT);
+    let n = builder.realpath_raw(b"d/up/..", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/");
+
+    // The device can't be checked
+    builder.flags(RealpathFlags::NO_XDEV);
+    assert_eq!(
+        builder.realpath_raw(b"d", &mut buf).map_err(|e| e.errno()),
+        Err(libc::ENOTSUP)
+    );
+
+    #[cfg(feature = "std")]
+    {
+        let mut builder = realpath_ext::RealpathBuilder::new();
+        builder.backend(Some(std::sync::Arc::new(FixtureBackend)));
+        assert_eq!(builder.realpath("d/up/l").unwrap().as_os_str(), "/real");
+        assert_eq!(
+            builder.realpath("nonexistent").unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+
+        let (in_root, host) = builder.realpath_in_root("/d/up/../d").unwrap();
+        assert_eq!(in_root.as_os_str(), "/d");
+        assert_eq!(host.as_os_str(), "/base/d");
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_memory_fs() {
+    use realpath_ext::{MemoryFs, RealpathRawBuilder};
+
+    let mut memfs = MemoryFs::new();
+    memfs.mkdir("/data", 0o755).unwrap();
+    memfs.mkdir("/data/me", 0o755).unwrap();
+    memfs.mkdir("/data/me/locked", 0o000).unwrap();
+    memfs.create_file("/data/me/file", 0o644).unwrap();
+    memfs.symlink("/data/me", "/home").unwrap();
+    memfs.symlink("../me/file", "/data/me/link").unwrap();
+    memfs.symlink("bad", "/data/me/bad").unwrap();
+    memfs.inject_error("/data/me/bad", Some(libc::EIO)).unwrap();
+    memfs.chdir("/home").unwrap();
+
+    let mut buf = [0; 100];
+    let mut builder = RealpathRawBuilder::new();
+    builder.backend(Some(&memfs));
+
+    let n = builder.realpath_raw(b"link", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/data/me/file");
+    let n = builder.realpath_raw(b"../../home/.", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/data/me");
+    assert_eq!(
+        builder
+            .realpath_raw(b"link/", &mut buf)
+            .map_err(|e| e.errno()),
+        Err(libc::ENOTDIR)
+    );
+    assert_eq!(
+        builder
+            .realpath_raw(b"bad", &mut buf)
+            .map_err(|e| e.errno()),
+        Err(libc::EIO)
+    );
+    assert_eq!(
+        builder
+            .realpath_raw(b"locked/x", &mut buf)
+            .map_err(|e| e.errno()),
+        Err(libc::EACCES)
+    );
+
+    builder.flags(RealpathFlags::ALLOW_MISSING);
+    let n = builder.realpath_raw(b"locked/x/../y", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/data/me/locked/y");
+    assert_eq!(
+        builder
+            .realpath_raw(b"bad", &mut buf)
+            .map_err(|e| e.errno()),
+        Err(libc::EIO)
+    );
+
+    builder.flags(RealpathFlags::ALLOW_LAST_MISSING);
+    let n = builder.realpath_raw(b"/home/new", &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"/data/me/new");
+
+    // The free functions behave the same way
+    let n =
+        realpath_ext::realpath_raw_with_backend(&memfs, b"link", &mut buf, RealpathFlags::empty())
+            .unwrap();
+    assert_eq!(&buf[..n], b"/data/me/file");
+    assert_eq!(
+        realpath_ext::realpath_with_backend(&memfs, "locked/x", RealpathFlags::empty())
+            .unwrap_err()
+            .errno(),
+        libc::EACCES
+    );
+    assert_eq!(
+        realpath_ext::realpath_with_backend(&memfs, "locked/x", RealpathFlags::ALLOW_MISSING)
+            .unwrap()
+            .as_os_str(),
+        "/data/me/locked/x"
+    );
+
+    // Errors getting the current directory are reported
+    memfs.inject_error("/data", Some(libc::EIO)).unwrap();
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.backend(Some(std::sync::Arc::new(memfs)));
+    assert_eq!(
+        builder.realpath("..").unwrap_err().raw_os_error(),
+        Some(libc::EIO)
+    );
+}
+
+/// Resolve `path` by driving a `Resolver` manually, performing each request with `backend`.
+#[cfg(feature = "std")]
+fn resolve_sans_io<B: realpath_ext::FsBackend>(
+    backend: &B,
+    path: &[u8],
+    flags: RealpathFlags,
+    requests: &mut Vec<String>,
+) -> Result<Vec<u8>, i32> {
+    use realpath_ext::{ResolveStep, Resolver};
+
+    let mut buf = [0; 4096];
+    let mut tmp = [0; 4096 + 100];
+    let mut resolver = Resolver::new(path, &mut buf, &mut tmp, flags)?;
+
+    let len = loop {
+        match resolver.step()? {
+            ResolveStep::NeedReadlink(path) => {
+                requests.push(format!("readlink {}", path.to_str().unwrap()));
+                let mut target = [0; 4096];
+                let res = backend.readlink(path, &mut target).map(|n| &target[..n]);
+                resolver.feed_readlink(res);
+            }
+            ResolveStep::NeedIsDir(path) => {
+                requests.push(format!("isdir {}", path.to_str().unwrap()));
+                let res = backend.check_isdir(path);
+                resolver.feed_isdir(res);
+            }
+            ResolveStep::NeedCwd => {
+                requests.push("cwd".to_string());
+                let mut cwd = [0; 4096];
+                let res = backend.getcwd(&mut cwd).map(|n| &cwd[..n]);
+                resolver.feed_cwd(res);
+            }
+            ResolveStep::NeedDevice(path) => {
+                requests.push(format!("device {}", path.to_str().unwrap()));
+                let res = backend.device(path);
+                resolver.feed_device(res);
+            }
+            ResolveStep::NeedIsProcfs(path) => {
+                requests.push(format!("is_procfs {}", path.to_str().unwrap()));
+                let res = backend.is_procfs(path);
+                resolver.feed_is_procfs(res);
+            }
+            ResolveStep::Done(len) => break len,
+        }
+    };
+
+    Ok(buf[..len].to_vec())
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_resolver() {
+    use realpath_ext::{MemoryFs, RealpathRawBuilder};
+
+    let mut memfs = MemoryFs::new();
+    memfs.mkdir("/a", 0o755).unwrap();
+    memfs.mkdir("/a/b", 0o755).unwrap();
+    memfs.mkdir("/a/locked", 0o000).unwrap();
+    memfs.create_file("/a/file", 0o644).unwrap();
+    memfs.symlink("b", "/a/l1").unwrap();
+    memfs.symlink("//a/l1/../file", "/a/b/l2").unwrap();
+    memfs.symlink("l3", "/a/l3").unwrap();
+    memfs.chdir("/a/b").unwrap();
+
+    let mut requests = Vec::new();
+    assert_eq!(
+        resolve_sans_io(&memfs, b"../l1/", RealpathFlags::empty(), &mut requests).unwrap(),
+        b"/a/b"
+    );
+    assert_eq!(
+        requests,
+        ["readlink ../l1", "readlink ../b", "isdir ../b", "cwd"]
+    );
+
+    requests.clear();
+    assert_eq!(
+        resolve_sans_io(&memfs, b"l2", RealpathFlags::NO_XDEV, &mut requests).unwrap(),
+        b"//a/file"
+    );
+    assert_eq!(
+        requests,
+        [
+            "device .",
+            "readlink l2",
+            "device /",
+            "readlink //a",
+            "device //a",
+            "readlink //a/l1",
+            "readlink //a/b",
+            "device //a/b",
+            "readlink //a/file",
+            "device //a/file",
+        ]
+    );
+
+    // Absolute paths start from the device of the root directory
+    requests.clear();
+    assert_eq!(
+        resolve_sans_io(&memfs, b"/a/file", RealpathFlags::NO_XDEV, &mut requests).unwrap(),
+        b"/a/file"
+    );
+    assert_eq!(
+        requests,
+        [
+            "device /",
+            "device /",
+            "readlink /a",
+            "device /a",
+            "readlink /a/file",
+            "device /a/file",
+        ]
+    );
+
+    // The results should be exactly the same as those of realpath_raw()
+    let mut buf = [0; 4096];
+    for &path in [
+        &b"."[..],
+        b"..",
+        b"../..",
+        b"../../..",
+        b"/",
+        b"//",
+        b"///",
+        b"l2",
+        b"l2/",
+        b"../l1/l2/x",
+        b"../l3",
+        b"../locked/x/",
+        b"../nonexistent/",
+        b"../file/.",
+        b"//a/l1/..",
+    ]
+    .iter()
+    {
+        for &flags in [
+            RealpathFlags::empty(),
+            RealpathFlags::ALLOW_MISSING,
+            RealpathFlags::ALLOW_LAST_MISSING,
+            RealpathFlags::IGNORE_SYMLINKS,
+            RealpathFlags::NO_SYMLINKS,
+            RealpathFlags::IN_ROOT,
+            RealpathFlags::BENEATH,
+            RealpathFlags::NO_XDEV,
+        ]
+        .iter()
+        {
+            let expected = RealpathRawBuilder::new()
+                .backend(Some(&memfs))
+                .flags(flags)
+                .realpath_raw(path, &mut buf)
+                .map(|n| buf[..n].to_vec())
+                .map_err(|e| e.errno());
+            assert_eq!(
+                resolve_sans_io(&memfs, path, flags, &mut Vec::new()),
+                expected,
+                "{:?} {:?}",
+                std::ffi::OsStr::from_bytes(path),
+                flags
+            );
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "does not match")]
+fn test_resolver_wrong_response() {
+    let mut buf = [0; 100];
+    let mut tmp = [0; 200];
+    let mut resolver =
+        realpath_ext::Resolver::new(b"a", &mut buf, &mut tmp, RealpathFlags::empty()).unwrap();
+    assert!(matches!(
+        resolver.step(),
+        Ok(realpath_ext::ResolveStep::NeedReadlink(_))
+    ));
+    resolver.feed_isdir(Ok(()));
+}
+
+/// A backend that counts the number of calls to readlink()
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Counting(std::sync::atomic::AtomicUsize);
+
+#[cfg(feature = "std")]
+impl realpath_ext::FsBackend for Counting {
+    fn readlink(&self, path: &std::ffi::CStr, buf: &mut [u8]) -> Result<usize, i32> {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        realpath_ext::LibcBackend::new().readlink(path, buf)
+    }
+
+    fn check_isdir(&self, path: &std::ffi::CStr) -> Result<(), i32> {
+        realpath_ext::LibcBackend::new().check_isdir(path)
+    }
+
+    fn getcwd(&self, buf: &mut [u8]) -> Result<usize, i32> {
+        realpath_ext::LibcBackend::new().getcwd(buf)
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_resolve_cache() {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use realpath_ext::ResolveCache;
+
+    let tmpdir = make_tmpdir("cache");
+    fs::create_dir(tmpdir.join("b")).unwrap();
+    fs::create_dir(tmpdir.join("c")).unwrap();
+    std::os::unix::fs::symlink("b", tmpdir.join("a")).unwrap();
+
+    let counting = Arc::new(Counting::default());
+    let cache = Arc::new(ResolveCache::new(100));
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder
+        .backend(Some(counting.clone()))
+        .cache(Some(cache.clone()));
+
+    assert_eq!(
+        builder.realpath(tmpdir.join("a")).unwrap(),
+        tmpdir.join("b")
+    );
+    let count = counting.0.load(Ordering::SeqCst);
+    assert!(count > 0);
+    assert!(!cache.is_empty());
+
+    // Everything should come from the cache
+    assert_eq!(
+        builder.realpath(tmpdir.join("a/.")).unwrap(),
+        tmpdir.join("b")
+    );
+    assert_eq!(counting.0.load(Ordering::SeqCst), count);
+
+    // Until it's invalidated
+    fs::remove_file(tmpdir.join("a")).unwrap();
+    std::os::unix::fs::symlink("c", tmpdir.join("a")).unwrap();
+    assert_eq!(
+        builder.realpath(tmpdir.join("a")).unwrap(),
+        tmpdir.join("b")
+    );
+    cache.invalidate(tmpdir.join("a"));
+    assert_eq!(
+        builder.realpath(tmpdir.join("a")).unwrap(),
+        tmpdir.join("c")
+    );
+    assert_eq!(counting.0.load(Ordering::SeqCst), count + 2);
+
+    // The cache also works with the default backend
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.cache(Some(cache.clone()));
+    cache.clear();
+    assert_eq!(
+        builder.realpath(tmpdir.join("a")).unwrap(),
+        tmpdir.join("c")
+    );
+    assert!(!cache.is_empty());
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+#[test]
+fn test_resolve_cache_inotify() {
+    use std::sync::Arc;
+
+    use realpath_ext::ResolveCache;
+
+    let tmpdir = make_tmpdir("cache-inotify");
+    fs::create_dir_all(tmpdir.join("b/x")).unwrap();
+    fs::create_dir_all(tmpdir.join("c/x")).unwrap();
+    fs::create_dir_all(tmpdir.join("d/e")).unwrap();
+    std::os::unix::fs::symlink("b", tmpdir.join("a")).unwrap();
+
+    let cache = Arc::new(ResolveCache::with_inotify(100).unwrap());
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.cache(Some(cache.clone()));
+
+    assert_eq!(
+        builder.realpath(tmpdir.join("a/x")).unwrap(),
+        tmpdir.join("b/x")
+    );
+    assert_eq!(
+        builder.realpath(tmpdir.join("d/e")).unwrap(),
+        tmpdir.join("d/e")
+    );
+    let len = cache.len();
+    assert!(len > 0);
+
+    // Replacing a symlink
+    fs::remove_file(tmpdir.join("a")).unwrap();
+    std::os::unix::fs::symlink("c", tmpdir.join("a")).unwrap();
+    assert_eq!(
+        builder.realpath(tmpdir.join("a/x")).unwrap(),
+        tmpdir.join("c/x")
+    );
+
+    // Replacing a directory with a symlink (which also invalidates everything beneath it)
+    fs::rename(tmpdir.join("d"), tmpdir.join("d2")).unwrap();
+    std::os::unix::fs::symlink("c", tmpdir.join("d")).unwrap();
+    assert_eq!(
+        builder.realpath(tmpdir.join("d/x")).unwrap(),
+        tmpdir.join("c/x")
+    );
+    assert_eq!(
+        builder
+            .realpath(tmpdir.join("d/e"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+
+    // Removing everything
+    fs::remove_dir_all(&tmpdir).unwrap();
+    assert_eq!(
+        builder
+            .realpath(tmpdir.join("a"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert!(cache.len() < len);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_realpath_many() {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    let tmpdir = make_tmpdir("many");
+    fs::create_dir_all(tmpdir.join("d/e/f")).unwrap();
+    fs::write(tmpdir.join("d/e/file"), b"").unwrap();
+    std::os::unix::fs::symlink("d/e", tmpdir.join("link")).unwrap();
+    std::os::unix::fs::symlink("../link", tmpdir.join("d/up")).unwrap();
+    std::os::unix::fs::symlink("loop", tmpdir.join("loop")).unwrap();
+
+    let names = [
+        "d/e/f",
+        "link/f",
+        "link/f/..",
+        "link/../d",
+        "d/up/f/",
+        "d/up/file",
+        "d/up/file/",
+        "d/e/missing",
+        "d/e/missing/x",
+        "d/missing/x/y",
+        "link//f/.",
+        "loop/x",
+        "d/e/f",
+        "d/up",
+    ];
+    let mut paths = names
+        .iter()
+        .map(|name| tmpdir.join(name))
+        .collect::<Vec<_>>();
+    paths.push("/".into());
+    paths.push("//".into());
+    paths.push(".".into());
+    paths.push("".into());
+
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    for &flags in [
+        RealpathFlags::empty(),
+        RealpathFlags::ALLOW_LAST_MISSING,
+        RealpathFlags::ALLOW_MISSING,
+        RealpathFlags::IGNORE_SYMLINKS,
+        RealpathFlags::NO_XDEV,
+    ]
+    .iter()
+    {
+        builder.flags(flags);
+
+        let results = builder.realpath_many(&paths);
+        assert_eq!(results.len(), paths.len());
+        for (path, res) in paths.iter().zip(results) {
+            let expected = builder.realpath(path);
+            assert_eq!(
+                res.as_ref().map_err(|e| e.raw_os_error()),
+                expected.as_ref().map_err(|e| e.raw_os_error()),
+                "{:?} {:?}",
+                path,
+                flags
+            );
+        }
+    }
+
+    // Inside a root directory
+    builder.flags(RealpathFlags::empty());
+    builder.root_dir(Some(tmpdir.join("d")));
+    let results = builder.realpath_many(["/e/f", "/../e/file", "/missing", "/e/f/.."]);
+    assert_eq!(results[0].as_ref().unwrap(), std::path::Path::new("/e/f"));
+    assert_eq!(
+        results[1].as_ref().unwrap(),
+        std::path::Path::new("/e/file")
+    );
+    assert_eq!(
+        results[2].as_ref().unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(results[3].as_ref().unwrap(), std::path::Path::new("/e"));
+
+    // Shared prefixes are only resolved once
+    let counting = Arc::new(Counting::default());
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.backend(Some(counting.clone()));
+
+    let files = (0..10)
+        .map(|i| tmpdir.join(format!("link/f/file{}", i)))
+        .collect::<Vec<_>>();
+    for file in files.iter() {
+        fs::write(file, b"").unwrap();
+    }
+
+    builder.realpath(&files[0]).unwrap();
+    let single = counting.0.swap(0, Ordering::SeqCst);
+
+    for (file, res) in files.iter().zip(builder.realpath_many(&files)) {
+        assert_eq!(
+            res.unwrap(),
+            tmpdir.join("d/e/f").join(file.file_name().unwrap())
+        );
+    }
+    assert_eq!(counting.0.load(Ordering::SeqCst), single + files.len() - 1);
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_realpath_many_parallel() {
+    let tmpdir = make_tmpdir("many-parallel");
+    let mut paths = Vec::new();
+    for i in 0..10 {
+        let dir = tmpdir.join(format!("dir{}", i));
+        fs::create_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, tmpdir.join(format!("link{}", i))).unwrap();
+
+        for j in 0..50 {
+            fs::write(dir.join(format!("file{}", j)), b"").unwrap();
+            paths.push(tmpdir.join(format!("link{}/file{}", i, j)));
+            paths.push(tmpdir.join(format!("dir{}/missing{}", i, j)));
+        }
+    }
+    paths.reverse();
+
+    let builder = realpath_ext::RealpathBuilder::new();
+    let expected = paths
+        .iter()
+        .map(|path| builder.realpath(path).map_err(|e| e.raw_os_error()))
+        .collect::<Vec<_>>();
+
+    for &threads in [0, 1, 2, 3, 8].iter() {
+        let results = builder
+            .realpath_many_parallel(&paths, threads)
+            .into_iter()
+            .map(|res| res.map_err(|e| e.raw_os_error()))
+            .collect::<Vec<_>>();
+        assert_eq!(results, expected);
+    }
+
+    assert!(builder.realpath_many_parallel(&[] as &[&str], 4).is_empty());
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[test]
+fn test_relpath() {
+    let tmpdir = std::env::temp_dir().join(format!("realpath-ext-relpath-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&tmpdir);
+    fs::create_dir_all(tmpdir.join("d/e/f")).unwrap();
+    std::os::unix::fs::symlink("d/e", tmpdir.join("link")).unwrap();
+
+    let relpath = |target: &str, base: &str, flags| {
+        let target = tmpdir.join(target);
+        let base = tmpdir.join(base);
+        let mut buf = vec![0; libc::PATH_MAX as usize];
+        realpath_ext::relpath_raw(
+            target.as_os_str().as_bytes(),
+            base.as_os_str().as_bytes(),
+            &mut buf,
+            flags,
+        )
+        .map(|n| {
+            buf.truncate(n);
+            OsString::from_vec(buf)
+        })
+    };
+
+    assert_eq!(
+        relpath("link/f", "d", RealpathFlags::empty()).unwrap(),
+        "e/f"
+    );
+    assert_eq!(
+        relpath("d", "link/f", RealpathFlags::empty()).unwrap(),
+        "../.."
+    );
+    assert_eq!(
+        relpath("link/..", "link/f/", RealpathFlags::empty()).unwrap(),
+        "../.."
+    );
+    assert_eq!(relpath("link", "d/e", RealpathFlags::empty()).unwrap(), ".");
+    assert_eq!(
+        relpath(".", "link", RealpathFlags::empty()).unwrap(),
+        "../.."
+    );
+    assert_eq!(
+        relpath("link", "link", RealpathFlags::IGNORE_SYMLINKS).unwrap(),
+        "."
+    );
+    assert_eq!(
+        relpath("link/f", "d", RealpathFlags::IGNORE_SYMLINKS).unwrap(),
+        "../link/f"
+    );
+
+    assert_eq!(
+        relpath("missing/x", "link", RealpathFlags::empty()).map_err(|e| e.errno()),
+        Err(libc::ENOENT)
+    );
+    assert_eq!(
+        relpath("link", "missing/x", RealpathFlags::empty()).map_err(|e| e.errno()),
+        Err(libc::ENOENT)
+    );
+    assert_eq!(
+        relpath("missing/x", "link", RealpathFlags::ALLOW_MISSING).unwrap(),
+        "../../missing/x"
+    );
+
+    // Both canonicalized paths have to fit in the buffer at once
+    let mut buf = [0; 5];
+    assert_eq!(
+        realpath_ext::relpath_raw(b"/", b"/dev/", &mut buf, RealpathFlags::empty()),
+        Ok(2)
+    );
+    assert_eq!(&buf[..2], b"..");
+    assert_eq!(
+        realpath_ext::relpath_raw(b"/", b"/dev/", &mut buf[..4], RealpathFlags::empty())
+            .map_err(|e| e.errno()),
+        Err(libc::ENAMETOOLONG)
+    );
+
+    #[cfg(feature = "std")]
+    {
+        assert_eq!(
+            realpath_ext::relpath(
+                tmpdir.join("d/e/f"),
+                tmpdir.join("link/f/."),
+                RealpathFlags::empty()
+            )
+            .unwrap()
+            .as_os_str(),
+            "."
+        );
+        assert_eq!(
+            realpath_ext::relpath(tmpdir.join("d"), "/", RealpathFlags::empty()).unwrap(),
+            fs::canonicalize(&tmpdir)
+                .unwrap()
+                .join("d")
+                .strip_prefix("/")
+                .unwrap()
+        );
+
+        let mut builder = realpath_ext::RealpathBuilder::new();
+        builder.root_dir(Some(tmpdir.clone()));
+        assert_eq!(
+            builder.relpath("/link/f", "/../d/").unwrap().as_os_str(),
+            "e/f"
+        );
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[test]
+fn test_logical() {
+    let tmpdir = std::env::temp_dir().join(format!("realpath-ext-logical-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&tmpdir);
+    fs::create_dir_all(tmpdir.join("a/b")).unwrap();
+    fs::create_dir_all(tmpdir.join("c/d")).unwrap();
+    std::os::unix::fs::symlink("../c/d", tmpdir.join("a/link")).unwrap();
+    std::os::unix::fs::symlink("d/..", tmpdir.join("c/up")).unwrap();
+    let tmpdir = fs::canonicalize(&tmpdir).unwrap();
+
+    let logical = |path: &str, flags| realpath(tmpdir.join(path), flags | RealpathFlags::LOGICAL);
+
+    for &(path, physical, expected) in [
+        ("a/link", "c/d", "c/d"),
+        ("a/link/..", "c", "a"),
+        ("a/link/../b", "", "a/b"),
+        ("a/link/d/../..", "", "a"),
+        ("a/link/../link/", "", "c/d"),
+        ("a/missing/../b", "", "a/b"),
+        ("a/missing/x/../../link", "", "c/d"),
+        ("a/link/d/..", "", "c/d"),
+        // Symlink targets are resolved physically
+        ("c/up", "c", "c"),
+        ("a/b/../../c/up/d", "c/d", "c/d"),
+    ]
+    .iter()
+    {
+        match realpath(tmpdir.join(path), RealpathFlags::empty()) {
+            Ok(res) => assert_eq!(res, tmpdir.join(physical), "{}", path),
+            Err(_) => assert_eq!(physical, "", "{}", path),
+        }
+        assert_eq!(
+            logical(path, RealpathFlags::empty()).unwrap(),
+            tmpdir.join(expected),
+            "{}",
+            path
+        );
+    }
+
+    assert_eq!(
+        logical("a/link/../missing", RealpathFlags::empty())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(
+        logical("a/link/../missing", RealpathFlags::ALLOW_LAST_MISSING).unwrap(),
+        tmpdir.join("a/missing")
+    );
+    assert_eq!(
+        logical("a/missing/x/..", RealpathFlags::ALLOW_LAST_MISSING).unwrap(),
+        tmpdir.join("a/missing")
+    );
+    assert_eq!(
+        logical("a/missing/..", RealpathFlags::ALLOW_LAST_MISSING).unwrap(),
+        tmpdir.join("a")
+    );
+    assert_eq!(
+        realpath("/../..", RealpathFlags::LOGICAL)
+            .unwrap()
+            .as_os_str(),
+        "/"
+    );
+
+    #[cfg(feature = "std")]
+    {
+        // The batch API must not reuse prefixes that are cancelled out
+        let mut builder = realpath_ext::RealpathBuilder::new();
+        builder.flags(RealpathFlags::LOGICAL);
+        let paths = [
+            "a/link/d",
+            "a/link/../b",
+            "a/link/x/../..",
+            "a/link/x/y/../../d",
+            "a/link",
+        ]
+        .iter()
+        .map(|path| tmpdir.join(path))
+        .collect::<Vec<_>>();
+        for (path, res) in paths.iter().zip(builder.realpath_many(&paths)) {
+            assert_eq!(
+                res.map_err(|e| e.raw_os_error()),
+                builder.realpath(path).map_err(|e| e.raw_os_error()),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_base_dir() {
+    let tmpdir = make_tmpdir("base-dir");
+    fs::create_dir_all(tmpdir.join("proj/a")).unwrap();
+    fs::create_dir(tmpdir.join("other")).unwrap();
+    fs::write(tmpdir.join("file"), b"").unwrap();
+    std::os::unix::fs::symlink("proj", tmpdir.join("link")).unwrap();
+    std::os::unix::fs::symlink("../other", tmpdir.join("proj/up")).unwrap();
+
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.base_dir(Some(tmpdir.join("link"))).unwrap();
+
+    let paths = [
+        ".",
+        "a",
+        "a/..",
+        "..",
+        "../..",
+        "../other",
+        "up",
+        "up/../proj/a",
+        "/",
+    ];
+    let expected = [
+        tmpdir.join("proj"),
+        tmpdir.join("proj/a"),
+        tmpdir.join("proj"),
+        tmpdir.clone(),
+        tmpdir.parent().unwrap().to_path_buf(),
+        tmpdir.join("other"),
+        tmpdir.join("other"),
+        tmpdir.join("proj/a"),
+        "/".into(),
+    ];
+    for (path, expected) in paths.iter().zip(expected.iter()) {
+        assert_eq!(&builder.realpath(path).unwrap(), expected, "{}", path);
+    }
+    for (res, expected) in builder
+        .realpath_many(paths)
+        .into_iter()
+        .zip(expected.iter())
+    {
+        assert_eq!(&res.unwrap(), expected);
+    }
+
+    assert_eq!(
+        builder.realpath_in_root("../a").unwrap(),
+        ("/a".into(), tmpdir.join("proj/a"))
+    );
+
+    // The returned paths start with the recorded path, even with the openat2() fast path
+    fs::create_dir(tmpdir.join("moved")).unwrap();
+    builder.base_dir(Some(tmpdir.join("moved"))).unwrap();
+    fs::rename(tmpdir.join("moved"), tmpdir.join("moved2")).unwrap();
+    for &openat2 in [false, true].iter() {
+        builder.use_openat2(openat2);
+        assert_eq!(builder.realpath(".").unwrap(), tmpdir.join("moved"));
+    }
+    builder.use_openat2(false);
+    builder.base_dir(Some(tmpdir.join("link"))).unwrap();
+
+    // Takes precedence over base_dir_fd()
+    let other = fs::File::open(tmpdir.join("other")).unwrap();
+    builder.base_dir_fd(Some(other.as_raw_fd()));
+    assert_eq!(builder.realpath("a").unwrap(), tmpdir.join("proj/a"));
+    builder.base_dir(None).unwrap();
+    assert_eq!(builder.realpath(".").unwrap(), tmpdir.join("other"));
+
+    for (dir, eno) in [("file", libc::ENOTDIR), ("missing", libc::ENOENT)].iter() {
+        assert_eq!(
+            builder
+                .base_dir(Some(tmpdir.join(dir)))
+                .err()
+                .and_then(|e| e.raw_os_error()),
+            Some(*eno)
+        );
+    }
+    // Failures leave the builder unchanged
+    assert_eq!(builder.realpath(".").unwrap(), tmpdir.join("other"));
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_use_pwd() {
+    let tmpdir = make_tmpdir("use-pwd");
+    let cwd = std::env::current_dir().unwrap();
+    let link = tmpdir.join("link");
+    std::os::unix::fs::symlink(&cwd, &link).unwrap();
+
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.use_pwd(true);
+
+    // Only this test touches $PWD (the working directory itself is left alone)
+    let old_pwd = std::env::var_os("PWD");
+    for (pwd, valid) in [
+        (link.clone(), true),
+        (link.join("."), false),
+        (link.join("src/.."), false),
+        (tmpdir.clone(), false),
+        ("link".into(), false),
+    ]
+    .iter()
+    {
+        std::env::set_var("PWD", pwd);
+        let valid = *valid;
+        let base = if valid { &link } else { &cwd };
+        assert_eq!(
+            builder.realpath("src").unwrap(),
+            base.join("src"),
+            "{:?}",
+            pwd
+        );
+        assert_eq!(builder.realpath("src/..").unwrap(), *base, "{:?}", pwd);
+        assert_eq!(
+            builder.realpath("../x").unwrap_err().raw_os_error(),
+            Some(libc::ENOENT),
+        );
+        // Absolute paths are unaffected
+        assert_eq!(builder.realpath(&link).unwrap(), cwd);
+    }
+    std::env::set_var("PWD", &link);
+    // Leading `..` components are resolved by the OS...
+    assert_eq!(builder.realpath("..").unwrap(), cwd.parent().unwrap());
+    assert_eq!(
+        builder
+            .realpath(
+                std::path::Path::new("..")
+                    .join(cwd.file_name().unwrap())
+                    .join("src")
+            )
+            .unwrap(),
+        cwd.join("src")
+    );
+    assert_eq!(
+        builder.realpath("../link").unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(
+        builder.realpath("src/../../x").unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    // ...unless LOGICAL is passed, in which case they are looked up relative to $PWD
+    builder.flags(RealpathFlags::LOGICAL);
+    assert_eq!(builder.realpath("..").unwrap(), tmpdir);
+    assert_eq!(builder.realpath("../link/src").unwrap(), cwd.join("src"));
+    assert_eq!(
+        builder
+            .realpath(format!(
+                "../../{}",
+                tmpdir.file_name().unwrap().to_str().unwrap()
+            ))
+            .unwrap(),
+        tmpdir
+    );
+    builder.flags(RealpathFlags::empty());
+    assert_eq!(
+        builder.realpath_many(["src", "Cargo.toml"])[1]
+            .as_ref()
+            .unwrap(),
+        &link.join("Cargo.toml")
+    );
+    builder.use_pwd(false);
+    assert_eq!(builder.realpath("src").unwrap(), cwd.join("src"));
+    match old_pwd {
+        Some(pwd) => std::env::set_var("PWD", pwd),
+        None => std::env::remove_var("PWD"),
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_error_context() {
+    let tmpdir = make_tmpdir("error-context");
+    fs::create_dir(tmpdir.join("d")).unwrap();
+    fs::write(tmpdir.join("file"), b"").unwrap();
+    std::os::unix::fs::symlink("d/missing/y", tmpdir.join("link")).unwrap();
+    std::os::unix::fs::symlink("loop", tmpdir.join("loop")).unwrap();
+
+    let tmp = tmpdir.as_os_str().as_bytes();
+    let join = |path: &str| match path {
+        "" => tmp.to_vec(),
+        _ => [tmp, b"/", path.as_bytes()].concat(),
+    };
+
+    let empty = RealpathFlags::empty();
+    let no_symlinks = RealpathFlags::NO_SYMLINKS;
+    for &(path, flags, eno, prefix, component, from_symlink) in [
+        ("missing/x", empty, libc::ENOENT, "", "missing", false),
+        ("file/x", empty, libc::ENOTDIR, "file", "x", false),
+        ("file/", empty, libc::ENOTDIR, "", "file", false),
+        ("./link/z", empty, libc::ENOENT, "d", "missing", true),
+        ("loop", empty, libc::ELOOP, "", "loop", true),
+        ("d/link", no_symlinks, libc::ENOENT, "d", "link", false),
+        ("link", no_symlinks, libc::ELOOP, "", "link", false),
+    ]
+    .iter()
+    {
+        let err = realpath(tmpdir.join(path), flags).unwrap_err();
+        assert_eq!(err.errno(), eno, "{}", path);
+        assert_eq!(err.prefix(), Some(&join(prefix)[..]), "{}", path);
+        assert_eq!(err.component(), Some(component.as_bytes()), "{}", path);
+        assert_eq!(err.from_symlink(), from_symlink, "{}", path);
+    }
+
+    // Relative prefixes are relative to the base directory
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.base_dir(Some(tmpdir.join("d"))).unwrap();
+    let err = builder.realpath("../missing/x").unwrap_err();
+    assert_eq!(err.prefix(), Some(&b".."[..]));
+    assert_eq!(err.component(), Some(&b"missing"[..]));
+
+    builder.flags(RealpathFlags::BENEATH);
+    let err = builder.realpath("../file").unwrap_err();
+    assert_eq!(err.errno(), libc::EXDEV);
+    assert_eq!(err.prefix(), Some(&b""[..]));
+    assert_eq!(err.component(), Some(&b".."[..]));
+
+    builder.flags(RealpathFlags::IN_ROOT);
+    let err = builder.realpath("/../../link/").unwrap_err();
+    assert_eq!(err.errno(), libc::ENOENT);
+    assert_eq!(err.prefix(), Some(&b"/"[..]));
+    assert_eq!(err.component(), Some(&b"link"[..]));
+
+    // Errors that aren't caused by a component have no context
+    let err = realpath("", RealpathFlags::empty()).unwrap_err();
+    assert_eq!(err.errno(), libc::ENOENT);
+    assert_eq!((err.prefix(), err.component()), (None, None));
+
+    let err = realpath(tmpdir.join("link"), RealpathFlags::empty()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "\"missing\" in {:?} (from a symbolic link): {}",
+            tmpdir.join("d"),
+            std::io::Error::from_raw_os_error(libc::ENOENT)
+        )
+    );
+    assert_eq!(std::io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_resolved() {
+    let tmpdir = make_tmpdir("resolved");
+    fs::create_dir(tmpdir.join("d")).unwrap();
+    fs::write(tmpdir.join("file"), b"").unwrap();
+    std::os::unix::fs::symlink("d/missing/y", tmpdir.join("link")).unwrap();
+
+    let tmp = tmpdir.as_os_str().as_bytes();
+    let join = |path: &str| match path {
+        "" => tmp.to_vec(),
+        _ => [tmp, b"/", path.as_bytes()].concat(),
+    };
+
+    let missing = RealpathFlags::ALLOW_MISSING;
+    let last_missing = RealpathFlags::ALLOW_LAST_MISSING;
+    for &(path, flags, resolved, existing, assumed) in [
+        ("d", missing, "d", "d", &[][..]),
+        (
+            "d/missing/a",
+            missing,
+            "d/missing/a",
+            "d",
+            &["missing", "a"][..],
+        ),
+        ("d/missing/../x/", missing, "d/x", "d", &["x"][..]),
+        ("d/missing/..", missing, "d", "d", &[][..]),
+        ("file/x", missing, "file/x", "file", &["x"][..]),
+        ("link", missing, "d/missing/y", "d", &["missing", "y"][..]),
+        ("d/new", last_missing, "d/new", "d", &["new"][..]),
+        ("new", last_missing, "new", "", &["new"][..]),
+    ]
+    .iter()
+    {
+        let mut builder = realpath_ext::RealpathBuilder::new();
+        builder.flags(flags);
+        let (path_buf, res) = builder.realpath_resolved(tmpdir.join(path)).unwrap();
+        let bytes = path_buf.as_os_str().as_bytes();
+        assert_eq!(bytes, join(resolved), "{}", path);
+        assert_eq!(res.len, bytes.len(), "{}", path);
+        assert_eq!(res.existing(bytes), join(existing), "{}", path);
+        assert_eq!(res.is_complete(), assumed.is_empty(), "{}", path);
+        assert!(
+            res.assumed(bytes).eq(assumed.iter().map(|c| c.as_bytes())),
+            "{}",
+            path
+        );
+
+        // The raw version gives the same result
+        let mut buf = [0; libc::PATH_MAX as usize];
+        let raw_res = realpath_ext::RealpathRawBuilder::new()
+            .flags(flags)
+            .realpath_raw_resolved(&join(path), &mut buf)
+            .unwrap();
+        assert_eq!(raw_res, res, "{}", path);
+    }
+
+    // Relative paths are adjusted when the path to the base directory is prepended
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder
+        .base_dir(Some(tmpdir.join("d")))
+        .unwrap()
+        .flags(missing);
+    for &(path, resolved, existing) in [
+        ("missing/a", "d/missing/a", "d"),
+        ("../missing/a", "missing/a", ""),
+        ("../d/missing", "d/missing", "d"),
+        ("missing/..", "d", "d"),
+    ]
+    .iter()
+    {
+        let (path_buf, res) = builder.realpath_resolved(path).unwrap();
+        let bytes = path_buf.as_os_str().as_bytes();
+        assert_eq!(bytes, join(resolved), "{}", path);
+        assert_eq!(res.existing(bytes), join(existing), "{}", path);
+    }
+
+    // Relative to the root directory
+    builder.flags(missing | RealpathFlags::IN_ROOT);
+    let (path_buf, res) = builder.realpath_resolved("/../missing/a").unwrap();
+    assert_eq!(path_buf, std::path::Path::new("/missing/a"));
+    assert_eq!(res.existing(path_buf.as_os_str().as_bytes()), b"/");
+}
+
+#[test]
+fn test_nofollow_last() {
+    let tmpdir = make_tmpdir("nofollow-last");
+    fs::create_dir(tmpdir.join("d")).unwrap();
+    fs::write(tmpdir.join("d/f"), b"").unwrap();
+    std::os::unix::fs::symlink("d", tmpdir.join("link")).unwrap();
+    std::os::unix::fs::symlink("link", tmpdir.join("link2")).unwrap();
+    std::os::unix::fs::symlink("f", tmpdir.join("d/flink")).unwrap();
+    std::os::unix::fs::symlink("missing", tmpdir.join("dangling")).unwrap();
+
+    let nofollow = RealpathFlags::NOFOLLOW_LAST;
+    for &(path, flags, expected) in [
+        ("link", nofollow, "link"),
+        ("link2", nofollow, "link2"),
+        ("link/flink", nofollow, "d/flink"),
+        ("link2/flink", nofollow, "d/flink"),
+        ("d/../link", nofollow, "link"),
+        ("dangling", nofollow, "dangling"),
+        ("link/", nofollow, "d"),
+        ("link/.", nofollow, "d"),
+        ("link/flink", RealpathFlags::empty(), "d/f"),
+        ("link", nofollow | RealpathFlags::NO_SYMLINKS, "link"),
+        ("link/x/..", nofollow | RealpathFlags::LOGICAL, "link"),
+        (
+            "d/missing",
+            nofollow | RealpathFlags::ALLOW_LAST_MISSING,
+            "d/missing",
+        ),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            realpath(tmpdir.join(path), flags).unwrap(),
+            tmpdir.join(expected),
+            "{}",
+            path
+        );
+    }
+
+    for &(path, flags, eno) in [
+        ("missing", nofollow, libc::ENOENT),
+        ("dangling/", nofollow, libc::ENOENT),
+        ("link/flink/", nofollow, libc::ENOTDIR),
+        (
+            "link/flink",
+            nofollow | RealpathFlags::NO_SYMLINKS,
+            libc::ELOOP,
+        ),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            realpath(tmpdir.join(path), flags).map_err(|e| e.errno()),
+            Err(eno),
+            "{}",
+            path
+        );
+    }
+
+    // The file descriptor refers to the link itself
+    #[cfg(all(feature = "std", target_os = "linux"))]
+    {
+        let (resolved, fd) = realpath_ext::RealpathBuilder::new()
+            .flags(nofollow)
+            .realpath_open(tmpdir.join("link2/flink"))
+            .unwrap();
+        assert_eq!(resolved, tmpdir.join("d/flink"));
+
+        let mut st = std::mem::MaybeUninit::uninit();
+        assert_eq!(unsafe { libc::fstat(fd.as_raw_fd(), st.as_mut_ptr()) }, 0);
+        let st = unsafe { st.assume_init() };
+        assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFLNK);
+
+        let (resolved, _) = realpath_ext::RealpathBuilder::new()
+            .flags(nofollow)
+            .realpath_open(tmpdir.join("link2/"))
+            .unwrap();
+        assert_eq!(resolved, tmpdir.join("d"));
+    }
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_limits() {
+    let tmpdir = make_tmpdir("limits");
+    fs::create_dir_all(tmpdir.join("d/e")).unwrap();
+    std::os::unix::fs::symlink("d", tmpdir.join("l3")).unwrap();
+    std::os::unix::fs::symlink("l3", tmpdir.join("l2")).unwrap();
+    std::os::unix::fs::symlink("l2", tmpdir.join("l1")).unwrap();
+
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.base_dir(Some(tmpdir.clone())).unwrap();
+    let dir = fs::File::open(&tmpdir).unwrap();
+
+    // "l1" -> "l2" -> "l3" -> "d" follows 3 symbolic links (with 5 bytes of targets), and
+    // processes 4 components
+    for &(max_symlinks, max_components, max_link_bytes, ok) in [
+        (None, None, None, true),
+        (Some(3), Some(4), Some(5), true),
+        (Some(2), None, None, false),
+        (None, Some(3), None, false),
+        (None, None, Some(4), false),
+    ]
+    .iter()
+    {
+        builder
+            .max_symlinks(max_symlinks)
+            .max_components(max_components)
+            .max_link_bytes(max_link_bytes);
+        let expected = if ok {
+            Ok(tmpdir.join("d"))
+        } else {
+            Err(libc::ELOOP)
+        };
+
+        assert_eq!(builder.realpath("l1").map_err(|e| e.errno()), expected);
+        assert_eq!(
+            builder
+                .clone()
+                .use_openat2(true)
+                .realpath("l1")
+                .map_err(|e| e.errno()),
+            expected
+        );
+        assert_eq!(
+            builder
+                .realpath_many(["l1", "l1"])
+                .into_iter()
+                .map(|res| res.map_err(|e| e.errno()))
+                .collect::<Vec<_>>(),
+            [expected.clone(), expected.clone()]
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            builder
+                .realpath_open("l1")
+                .map(|(path, _)| path)
+                .map_err(|e| e.raw_os_error().unwrap()),
+            expected
+        );
+
+        let mut buf = [0; libc::PATH_MAX as usize];
+        let res = realpath_ext::RealpathRawBuilder::new()
+            .base_dir_fd(Some(dir.as_raw_fd()))
+            .max_symlinks(max_symlinks)
+            .max_components(max_components)
+            .max_link_bytes(max_link_bytes)
+            .realpath_raw(b"l1", &mut buf);
+        assert_eq!(
+            res.map(|n| std::path::PathBuf::from(std::ffi::OsStr::from_bytes(&buf[..n])))
+                .map_err(|e| e.errno()),
+            expected
+        );
+    }
+
+    // `..` and `/` count as components, but `.` does not
+    builder
+        .max_symlinks(None)
+        .max_link_bytes(None)
+        .max_components(Some(3));
+    assert_eq!(builder.realpath("./d/./e/..").unwrap(), tmpdir.join("d"));
+    assert_eq!(
+        builder.realpath("d/e/../e").map_err(|e| e.errno()),
+        Err(libc::ELOOP)
+    );
+    builder.max_components(Some(1));
+    assert_eq!(builder.realpath("/").unwrap(), std::path::Path::new("/"));
+    assert_eq!(
+        builder.realpath("/.").map_err(|e| e.errno()),
+        Ok("/".into())
+    );
+    assert_eq!(
+        builder.realpath("/d").map_err(|e| e.errno()),
+        Err(libc::ELOOP)
+    );
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_symlink_cycle() {
+    let tmpdir = make_tmpdir("symlink-cycle");
+    fs::create_dir(tmpdir.join("d")).unwrap();
+    std::os::unix::fs::symlink("b", tmpdir.join("a")).unwrap();
+    std::os::unix::fs::symlink("c", tmpdir.join("b")).unwrap();
+    std::os::unix::fs::symlink("a", tmpdir.join("c")).unwrap();
+    std::os::unix::fs::symlink("self", tmpdir.join("self")).unwrap();
+    std::os::unix::fs::symlink("../d/x", tmpdir.join("d/x")).unwrap();
+    // Each time "grow" is followed, there is more of the path left to resolve
+    std::os::unix::fs::symlink("grow/x", tmpdir.join("grow")).unwrap();
+    for i in 0..8 {
+        std::os::unix::fs::symlink(format!("l{}", i + 1), tmpdir.join(format!("l{}", i))).unwrap();
+    }
+    std::os::unix::fs::symlink("d", tmpdir.join("l8")).unwrap();
+    // A hard link to a symbolic link is the same link under a different name
+    std::os::unix::fs::symlink("q", tmpdir.join("p")).unwrap();
+    fs::hard_link(tmpdir.join("p"), tmpdir.join("q")).unwrap();
+
+    fn cycle(err: &realpath_ext::RealpathError) -> Option<Vec<Vec<u8>>> {
+        err.symlink_cycle()
+            .map(|cycle| cycle.map(|link| link.to_vec()).collect())
+    }
+
+    let tmp = tmpdir.as_os_str().as_bytes();
+    let join = |path: &str| [tmp, b"/", path.as_bytes()].concat();
+
+    let mut builder = realpath_ext::RealpathBuilder::new();
+    builder.detect_symlink_cycles(true);
+
+    for &(path, expected) in [
+        ("a", &["a", "b", "c", "a"][..]),
+        ("b/", &["b", "c", "a", "b"][..]),
+        ("self", &["self", "self"][..]),
+        ("d/x/y", &["d/x", "d/x"][..]),
+        ("p", &["p", "q"][..]),
+    ]
+    .iter()
+    {
+        let expected = expected.iter().map(|link| join(link)).collect::<Vec<_>>();
+
+        let err = builder.realpath(tmpdir.join(path)).unwrap_err();
+        assert_eq!(err.errno(), libc::ELOOP, "{}", path);
+        assert_eq!(cycle(&err), Some(expected.clone()), "{}", path);
+
+        let err = builder
+            .realpath_many([tmpdir.join(path)])
+            .pop()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(cycle(&err), Some(expected.clone()), "{}", path);
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let err = builder.realpath_open(tmpdir.join(path)).unwrap_err();
+            assert_eq!(err.errno(), libc::ELOOP, "{}", path);
+            assert_eq!(cycle(&err), Some(expected), "{}", path);
+        }
+
+        // Loops are only looked for if enabled
+        let err = realpath(tmpdir.join(path), RealpathFlags::empty()).unwrap_err();
+        assert_eq!(err.errno(), libc::ELOOP, "{}", path);
+        assert!(err.symlink_cycle().is_none(), "{}", path);
+    }
+
+    // Paths are relative to the base directory
+    builder.base_dir(Some(tmpdir.clone())).unwrap();
+    let err = builder.realpath("c").unwrap_err();
+    assert_eq!(
+        cycle(&err),
+        Some(vec![
+            b"c".to_vec(),
+            b"a".to_vec(),
+            b"b".to_vec(),
+            b"c".to_vec()
+        ])
+    );
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "\"c\" (from a symbolic link): {} (loop: \"c\" -> \"a\" -> \"b\" -> \"c\")",
+            std::io::Error::from_raw_os_error(libc::ELOOP)
+        )
+    );
+
+    // Too many symbolic links, but no loop
+    builder.max_symlinks(Some(5));
+    let err = builder.realpath("l0").unwrap_err();
+    assert_eq!(err.errno(), libc::ELOOP);
+    assert!(err.symlink_cycle().is_none());
+    builder.max_symlinks(None);
+    assert_eq!(builder.realpath("l0").unwrap(), tmpdir.join("d"));
+    let err = builder.realpath("grow").unwrap_err();
+    assert_eq!(err.errno(), libc::ELOOP);
+    assert!(err.symlink_cycle().is_none());
+
+    // A loop that is longer than the limit can't be detected
+    builder.max_symlinks(Some(2));
+    assert!(builder.realpath("a").unwrap_err().symlink_cycle().is_none());
+    builder.max_symlinks(Some(3));
+    assert!(builder.realpath("a").unwrap_err().symlink_cycle().is_some());
+
+    fs::remove_dir_all(tmpdir).unwrap();
+}